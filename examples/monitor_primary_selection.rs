@@ -15,7 +15,7 @@ fn main() {
             clipboard.getter.atoms.utf8_string,
             clipboard.getter.atoms.property
         ) {
-            let curr = String::from_utf8_lossy(&curr);
+            let curr = String::from_utf8_lossy(&curr.bytes);
             let curr = curr
                 .trim_matches('\u{0}')
                 .trim();
@@ -14,7 +14,7 @@ fn main() {
             Duration::from_secs(3)
         )
         .unwrap();
-    let val = String::from_utf8(val).unwrap();
+    let val = String::from_utf8(val.bytes).unwrap();
 
     print!("{}", val);
 }
@@ -14,7 +14,7 @@ fn main() {
             )
             .unwrap();
 
-        let val = String::from_utf8(val).unwrap();
+        let val = String::from_utf8(val.bytes).unwrap();
 
         println!("{}", val);
     }
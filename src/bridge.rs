@@ -0,0 +1,249 @@
+use std::sync::{ Arc, Weak };
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::sync::mpsc::channel;
+use std::thread;
+use std::time::Instant;
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::{ AtomEnum, ConnectionExt };
+use ::{ Atom, Clipboard, RustConnection, Selection, SelectionData, Source, Watch, Window, COPY_TIMEOUT, is_value_target };
+use error::Error;
+
+
+/// Shares selections between two displays.
+///
+/// Ownership changes on either display are mirrored on the other one,
+/// values are only transferred when a requestor asks for them.
+///
+/// ```no_run
+/// # use x11_clipboard::{ Bridge, ClipboardBuilder, Selection };
+/// let first = ClipboardBuilder::new().display(":0").build().unwrap();
+/// let second = ClipboardBuilder::new().display(":1").build().unwrap();
+/// Bridge::new(first, second).run(&[Selection::Clipboard, Selection::Primary]).unwrap();
+/// ```
+pub struct Bridge<C: Connection = RustConnection> {
+    first: Arc<Clipboard<C>>,
+    second: Arc<Clipboard<C>>,
+}
+
+/// The clipboards a value is forwarded from and to.
+type Pair<C> = (Arc<Clipboard<C>>, Arc<Clipboard<C>>);
+
+/// Converts the value of a selection owned on the other display.
+struct Forward<C: Connection> {
+    from: Weak<Clipboard<C>>,
+    to: Weak<Clipboard<C>>,
+    selection: Atom,
+}
+
+impl<C: Connection + Send + Sync + 'static> Bridge<C> {
+    pub fn new(first: Clipboard<C>, second: Clipboard<C>) -> Bridge<C> {
+        Bridge { first: Arc::new(first), second: Arc::new(second) }
+    }
+
+    /// Mirror `selections` until an error occurs on either display, then stop both directions.
+    ///
+    /// Selections are matched by name, atoms of other selections are only valid on one display.
+    pub fn run(&self, selections: &[Selection]) -> Result<(), Error> {
+        let (sender, receiver) = channel();
+        let stop = Arc::new(AtomicBool::new(false));
+        let directions = [(&self.first, &self.second), (&self.second, &self.first)];
+        let handles = directions.iter()
+            .map(|&(from, to)| {
+                let (from, to, sender) = (Arc::clone(from), Arc::clone(to), sender.clone());
+                let (selections, stop) = (selections.to_vec(), Arc::clone(&stop));
+                thread::spawn(move || {
+                    let _ = sender.send(mirror(&from, &to, &selections, &stop));
+                })
+            })
+            .collect::<Vec<_>>();
+        drop(sender);
+
+        let result = receiver.recv().map_err(|_| Error::Disconnected);
+        stop.store(true, Ordering::Relaxed);
+        for handle in handles {
+            let _ = handle.join();
+        }
+        result?
+    }
+}
+
+/// Mirror the owners of `selections` on `from` to `to`, until `stop` is set.
+fn mirror<C>(from: &Arc<Clipboard<C>>, to: &Arc<Clipboard<C>>, selections: &[Selection], stop: &AtomicBool)
+    -> Result<(), Error>
+    where C: Connection + Send + Sync + 'static
+{
+    from.check_health()?;
+
+    let session = from.session()?;
+    let to_session = to.session()?;
+    let sources = selections.iter()
+        .map(|selection| session.getter.selection_atom(selection))
+        .collect::<Result<Vec<_>, _>>()?;
+    let destinations = selections.iter()
+        .map(|selection| to.draining(&to_session, || to_session.getter.selection_atom(selection)))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let request = session.dispatcher.register_watch(sources.clone())?;
+    let watches = sources.iter()
+        .map(|&selection| Watch::new(&session, selection))
+        .collect::<Result<Vec<_>, _>>()?;
+    if let Some(watch) = watches.last() {
+        request.set_sequence(watch.sequence_number)?;
+    }
+
+    // Selections owned before the watches started
+    for (&source, &destination) in sources.iter().zip(&destinations) {
+        let owner = session.getter.connection.get_selection_owner(source)?.reply()?.owner;
+        forward(from, to, source, destination, owner)?;
+    }
+
+    // Woken up every poll interval to check `stop`
+    while !stop.load(Ordering::Relaxed) {
+        let deadline = Instant::now() + from.config.poll_interval;
+        match from.wait_event(&session, &request, Some(deadline)) {
+            Ok(Event::XfixesSelectionNotify(event)) => {
+                if let Some(i) = sources.iter().position(|&source| source == event.selection) {
+                    forward(from, to, sources[i], destinations[i], event.owner)?;
+                }
+            },
+            Ok(_) | Err(Error::Timeout) => (),
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(())
+}
+
+/// Mirror a new `owner` of `source`, unless it is the bridge itself.
+pub(crate) fn forward<C>(from: &Arc<Clipboard<C>>, to: &Arc<Clipboard<C>>, source: Atom, destination: Atom, owner: Window)
+    -> Result<(), Error>
+    where C: Connection + Send + Sync + 'static
+{
+    // The setter window changes when the background thread reconnects
+    if owner == from.session()?.setter.window {
+        return Ok(());
+    }
+
+    if owner == x11rb::NONE {
+        // Nothing left to forward
+        return to.clear_stored(destination);
+    }
+
+    let forward = Forward { from: Arc::downgrade(from), to: Arc::downgrade(to), selection: source };
+    match to.store_source(destination, Arc::new(forward)) {
+        // Taken by another client meanwhile
+        Err(Error::Owner) => Ok(()),
+        result => result
+    }
+}
+
+impl<C: Connection> Forward<C> {
+    fn upgrade(&self) -> Result<Pair<C>, Error> {
+        match (self.from.upgrade(), self.to.upgrade()) {
+            (Some(from), Some(to)) => Ok((from, to)),
+            _ => Err(Error::Disconnected)
+        }
+    }
+}
+
+impl<C: Connection + Send + Sync> Source for Forward<C> {
+    fn targets(&self) -> Result<Vec<Atom>, Error> {
+        let (from, to) = self.upgrade()?;
+        let timeout = from.config.timeout.unwrap_or(COPY_TIMEOUT);
+
+        // Special and informational targets are answered by the owner on `to` itself
+        let targets = from.targets(self.selection, timeout)?;
+        let (from_session, to_session) = (from.session()?, to.session()?);
+        let names = from.draining(&from_session, || from_session.getter.get_atom_names(&targets))?
+            .into_iter()
+            .filter(|name| is_value_target(name))
+            .collect::<Vec<_>>();
+        to.draining(&to_session, || to_session.getter.get_atoms(&names))
+    }
+
+    /// Atoms are only valid on their display, the target, the type and lists of atoms are looked up by name.
+    fn convert(&self, target: Atom) -> Result<SelectionData, Error> {
+        let (from, to) = self.upgrade()?;
+        let timeout = from.config.timeout.unwrap_or(COPY_TIMEOUT);
+
+        let (from_session, to_session) = (from.session()?, to.session()?);
+        let (from_context, to_context) = (&from_session.getter, &to_session.getter);
+        let name = to.draining(&to_session, || to_context.get_atom_name(target))?;
+        let target = from.draining(&from_session, || from_context.get_atom(&name))?;
+        let mut data = from.load(self.selection, target, from_context.atoms.property, timeout)?;
+
+        let atoms = match data.as_atoms() {
+            Some(ref atoms) if data.type_ == Atom::from(AtomEnum::ATOM) => Some(atoms.clone()),
+            _ => None
+        };
+        let (type_name, atom_names) = from.draining(&from_session, || Ok((
+            from_context.get_atom_name(data.type_)?,
+            atoms.map(|atoms| from_context.get_atom_names(&atoms)).transpose()?
+        )))?;
+        to.draining(&to_session, || {
+            if let Some(names) = atom_names {
+                data = SelectionData::from_u32s(data.type_, &to_context.get_atoms(&names)?);
+            }
+            data.type_ = to_context.get_atom(&type_name)?;
+            Ok(data)
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::mpsc::channel;
+    use std::time::Duration;
+    use x11rb::protocol::xproto::ConnectionExt;
+    use ::{ AccessRequest, Clipboard, ClipboardBuilder, SideEffectRequest };
+    use error::Error;
+
+    /// A bridge matches the selections by name, so the two sides use different ones on a single display.
+    #[test]
+    fn forward_owner() {
+        let (sender, receiver) = channel();
+        let owner = ClipboardBuilder::new()
+            .audit(sender)
+            .info_targets(true)
+            .side_effects(|_: &SideEffectRequest| false)
+            .build()
+            .unwrap();
+        let atom_text = owner.setter.get_atom("text/plain").unwrap();
+        let from = Arc::new(Clipboard::new().unwrap());
+        let to = Arc::new(ClipboardBuilder::new()
+            .info_targets(false)
+            .access_policy(move |request: &AccessRequest| request.target != atom_text)
+            .build()
+            .unwrap());
+
+        let atom_source = owner.setter.get_atom("X11_CLIPBOARD_TEST_BRIDGE_SOURCE").unwrap();
+        let atom_destination = owner.setter.get_atom("X11_CLIPBOARD_TEST_BRIDGE_DESTINATION").unwrap();
+        let atom_utf8string = owner.setter.atoms.utf8_string;
+        let atom_targets = owner.getter.atoms.targets;
+        let atom_timestamp = owner.setter.get_atom("TIMESTAMP").unwrap();
+        let atom_property = from.getter.atoms.property;
+        let dur = Duration::from_secs(3);
+
+        owner.store_targets(atom_source, vec![(atom_utf8string, b"text".to_vec()), (atom_text, b"text".to_vec())]).unwrap();
+        let window = from.getter.connection.get_selection_owner(atom_source).unwrap().reply().unwrap().owner;
+        super::forward(&from, &to, atom_source, atom_destination, window).unwrap();
+
+        // Side effects and informational targets belong to the owner
+        let targets = from.targets(atom_destination, dur).unwrap();
+        assert_eq!(targets, [atom_targets, atom_timestamp, atom_utf8string, atom_text]);
+        while receiver.try_recv().is_ok() {}
+
+        // Denied requests are not forwarded
+        match from.load(atom_destination, atom_text, atom_property, dur) {
+            Err(Error::ConversionRefused { .. }) => (),
+            other => panic!("unexpected result: {:?}", other)
+        }
+        assert!(receiver.try_recv().is_err());
+
+        let data = from.load(atom_destination, atom_utf8string, atom_property, dur).unwrap();
+        assert_eq!((data.type_, data.bytes), (atom_utf8string, b"text".to_vec()));
+        assert_eq!(receiver.try_recv().unwrap().target, atom_utf8string);
+    }
+}
@@ -0,0 +1,201 @@
+use std::sync::Arc;
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use x11rb::connection::Connection;
+use ::{ Access, Clipboard, Window, INCR_CHUNK_SIZE, POLL_DURATION };
+use cache::COMMON_ATOMS;
+use policy::{ AccessPolicy, SharedPolicy };
+use effect::{ SharedEffects, SideEffects };
+#[cfg(feature = "xcb")]
+use ::XCBConnection;
+use error::Error;
+
+
+/// Configuration shared by the getter and the setter thread.
+#[derive(Clone, Debug)]
+pub(crate) struct Config {
+    pub(crate) display: Option<String>,
+    pub(crate) screen: Option<usize>,
+    pub(crate) property: String,
+    pub(crate) incr_chunk_size: usize,
+    pub(crate) incr_threshold: Option<usize>,
+    pub(crate) timeout: Option<Duration>,
+    pub(crate) poll_interval: Duration,
+    pub(crate) use_xfixes: bool,
+    pub(crate) reconnect: bool,
+    pub(crate) atoms: Vec<String>,
+    pub(crate) audit: Option<Sender<Access>>,
+    pub(crate) policy: Option<SharedPolicy>,
+    pub(crate) effects: Option<SharedEffects>,
+    pub(crate) info_targets: bool,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            display: None,
+            screen: None,
+            property: String::from("THIS_CLIPBOARD_OUT"),
+            incr_chunk_size: INCR_CHUNK_SIZE,
+            incr_threshold: None,
+            timeout: None,
+            poll_interval: Duration::from_millis(POLL_DURATION),
+            use_xfixes: true,
+            reconnect: false,
+            atoms: COMMON_ATOMS.iter().map(|&name| name.into()).collect(),
+            audit: None,
+            policy: None,
+            effects: None,
+            info_targets: true,
+        }
+    }
+}
+
+/// Clipboard Builder
+///
+/// ```no_run
+/// # use std::time::Duration;
+/// # use x11_clipboard::ClipboardBuilder;
+/// let clipboard = ClipboardBuilder::new()
+///     .display(":1")
+///     .timeout(Duration::from_secs(3))
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ClipboardBuilder {
+    config: Config,
+}
+
+impl ClipboardBuilder {
+    pub fn new() -> ClipboardBuilder {
+        ClipboardBuilder::default()
+    }
+
+    /// Display to connect to, `$DISPLAY` by default.
+    pub fn display<S: Into<String>>(mut self, name: S) -> ClipboardBuilder {
+        self.config.display = Some(name.into());
+        self
+    }
+
+    /// Screen to create the windows on, the display's default screen by default.
+    pub fn screen(mut self, screen: usize) -> ClipboardBuilder {
+        self.config.screen = Some(screen);
+        self
+    }
+
+    /// Name of the property atom, `THIS_CLIPBOARD_OUT` by default.
+    pub fn property<S: Into<String>>(mut self, name: S) -> ClipboardBuilder {
+        self.config.property = name.into();
+        self
+    }
+
+    /// Size of each chunk sent during an INCR transfer, `INCR_CHUNK_SIZE` by default.
+    pub fn incr_chunk_size(mut self, size: usize) -> ClipboardBuilder {
+        self.config.incr_chunk_size = size;
+        self
+    }
+
+    /// Values of at least this size are sent with INCR.
+    ///
+    /// It is always capped by the maximum request length of the server.
+    pub fn incr_threshold(mut self, size: usize) -> ClipboardBuilder {
+        self.config.incr_threshold = Some(size);
+        self
+    }
+
+    /// Timeout used by `load` when no timeout is given.
+    pub fn timeout(mut self, timeout: Duration) -> ClipboardBuilder {
+        self.config.timeout = Some(timeout);
+        self
+    }
+
+    /// Interval used when polling for events, 50ms by default.
+    pub fn poll_interval(mut self, interval: Duration) -> ClipboardBuilder {
+        self.config.poll_interval = interval;
+        self
+    }
+
+    /// Whether to use the XFIXES extension to wait for selection changes, `true` by default.
+    ///
+    /// Without it, `load_wait` polls the selection owner instead. This only notices a new owner window,
+    /// so a new value stored by the same window is missed, which is common since toolkits reuse theirs.
+    pub fn use_xfixes(mut self, flag: bool) -> ClipboardBuilder {
+        self.config.use_xfixes = flag;
+        self
+    }
+
+    /// Whether the background thread reconnects when its connection is lost, `false` by default.
+    ///
+    /// Both connections are opened again, see `Clipboard::context` for the new atoms.
+    /// Once reconnected, it takes back the selections at the current server time,
+    /// those whose atoms can't be looked up by name again are dropped.
+    /// Until then, and without this, the clipboard methods fail with `Error::Disconnected`.
+    pub fn reconnect(mut self, flag: bool) -> ClipboardBuilder {
+        self.config.reconnect = flag;
+        self
+    }
+
+    /// Atoms interned together at startup, common targets such as `text/plain` by default.
+    pub fn preload_atoms<I, S>(mut self, names: I) -> ClipboardBuilder
+        where I: IntoIterator<Item = S>, S: Into<String>
+    {
+        self.config.atoms = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Report every `SelectionRequest` answered for the stored values on `sender`.
+    ///
+    /// The PID and class of each requestor are looked up before answering it.
+    pub fn audit(mut self, sender: Sender<Access>) -> ClipboardBuilder {
+        self.config.audit = Some(sender);
+        self
+    }
+
+    /// Consult `policy` before answering each request for the stored values.
+    ///
+    /// The PID and class of each requestor are looked up before answering it.
+    pub fn access_policy<P: AccessPolicy + 'static>(mut self, policy: P) -> ClipboardBuilder {
+        self.config.policy = Some(SharedPolicy(Arc::new(policy)));
+        self
+    }
+
+    /// Perform the ICCCM side effects `DELETE`, `INSERT_SELECTION` and `INSERT_PROPERTY` with `effects`.
+    ///
+    /// Without it, these targets are refused.
+    pub fn side_effects<E: SideEffects + 'static>(mut self, effects: E) -> ClipboardBuilder {
+        self.config.effects = Some(SharedEffects(Arc::new(effects)));
+        self
+    }
+
+    /// Whether to answer the ICCCM targets describing the owner, `true` by default.
+    ///
+    /// These are `LENGTH`, `CLIENT_WINDOW`, `HOSTNAME`, `NAME`, `USER`, `PROCESS` and `OWNER_OS`,
+    /// `TIMESTAMP` is answered either way.
+    /// Turn it off to keep them private.
+    pub fn info_targets(mut self, flag: bool) -> ClipboardBuilder {
+        self.config.info_targets = flag;
+        self
+    }
+
+    /// Create Clipboard.
+    pub fn build(self) -> Result<Clipboard, Error> {
+        Clipboard::with_config(self.config)
+    }
+
+    /// Create Clipboard using libxcb connections.
+    #[cfg(feature = "xcb")]
+    pub fn build_xcb(self) -> Result<Clipboard<XCBConnection>, Error> {
+        Clipboard::with_xcb_config(self.config)
+    }
+
+    /// Create Clipboard on a connection shared with the application.
+    ///
+    /// The display and screen settings are ignored. If no window is given, one is created.
+    /// The application must pass the events of its connection to `Clipboard::handle_event_with_sequence`.
+    pub fn build_with_connection<C: Connection>(self, connection: Arc<C>, screen: usize, window: Option<Window>)
+        -> Result<Clipboard<C>, Error>
+    {
+        Clipboard::with_shared(self.config, connection, screen, window)
+    }
+}
@@ -0,0 +1,90 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::ConnectionExt;
+use ::Atom;
+use error::Error;
+
+
+/// Atoms interned at startup, unless configured otherwise.
+pub(crate) const COMMON_ATOMS: &[&str] = &[
+    "TEXT",
+    "COMPOUND_TEXT",
+    "TIMESTAMP",
+    "MULTIPLE",
+    "SAVE_TARGETS",
+    "text/plain",
+    "text/plain;charset=utf-8",
+    "text/html",
+    "text/uri-list",
+    "image/png",
+];
+
+/// Atoms and their names, shared by the connections to a display.
+///
+/// Atoms are global to the server, so the getter and the setter can use the same cache.
+#[derive(Default)]
+pub(crate) struct AtomCache {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    atoms: HashMap<String, Atom>,
+    names: HashMap<Atom, String>,
+}
+
+impl AtomCache {
+    fn insert(&self, name: &str, atom: Atom) -> Result<(), Error> {
+        let mut inner = self.inner.write().map_err(|_| Error::Lock)?;
+        inner.atoms.insert(name.into(), atom);
+        inner.names.insert(atom, name.into());
+        Ok(())
+    }
+
+    /// Intern the atoms missing from the cache, all the requests are sent before waiting for the replies.
+    pub(crate) fn intern_all<C: Connection, S: AsRef<str>>(&self, connection: &C, names: &[S])
+        -> Result<Vec<Atom>, Error>
+    {
+        let mut atoms = vec![x11rb::NONE; names.len()];
+        let mut cookies = Vec::new();
+        {
+            let inner = self.inner.read().map_err(|_| Error::Lock)?;
+            for (i, name) in names.iter().enumerate() {
+                match inner.atoms.get(name.as_ref()) {
+                    Some(&atom) => atoms[i] = atom,
+                    None => cookies.push((i, connection.intern_atom(false, name.as_ref().as_bytes())?))
+                }
+            }
+        }
+
+        for (i, cookie) in cookies {
+            let atom = cookie.reply()?.atom;
+            self.insert(names[i].as_ref(), atom)?;
+            atoms[i] = atom;
+        }
+        Ok(atoms)
+    }
+
+    /// Look up the names missing from the cache, all the requests are sent before waiting for the replies.
+    pub(crate) fn names<C: Connection>(&self, connection: &C, atoms: &[Atom]) -> Result<Vec<String>, Error> {
+        let mut names = vec![String::new(); atoms.len()];
+        let mut cookies = Vec::new();
+        {
+            let inner = self.inner.read().map_err(|_| Error::Lock)?;
+            for (i, &atom) in atoms.iter().enumerate() {
+                match inner.names.get(&atom) {
+                    Some(name) => names[i] = name.clone(),
+                    None => cookies.push((i, connection.get_atom_name(atom)?))
+                }
+            }
+        }
+
+        for (i, cookie) in cookies {
+            let name = String::from_utf8_lossy(&cookie.reply()?.name).into_owned();
+            self.insert(&name, atoms[i])?;
+            names[i] = name;
+        }
+        Ok(names)
+    }
+}
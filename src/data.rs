@@ -0,0 +1,94 @@
+use std::str;
+use ::Atom;
+use error::Error;
+
+
+/// A value loaded from a selection, with the type and format the owner gave it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SelectionData {
+    pub type_: Atom,
+    /// Size in bits of the elements of the value, 8, 16 or 32.
+    pub format: u8,
+    /// Elements of 16 or 32 bits are in native byte order.
+    pub bytes: Vec<u8>,
+}
+
+impl SelectionData {
+    /// Value of 8-bit elements.
+    pub fn new<T: Into<Vec<u8>>>(type_: Atom, bytes: T) -> SelectionData {
+        SelectionData { type_, format: 8, bytes: bytes.into() }
+    }
+
+    /// Value of 32-bit elements, such as `ATOM`, `INTEGER`, `WINDOW` or `ATOM_PAIR` values.
+    pub fn from_u32s(type_: Atom, values: &[u32]) -> SelectionData {
+        let bytes = values.iter().flat_map(|value| value.to_ne_bytes()).collect();
+        SelectionData { type_, format: 32, bytes }
+    }
+
+    /// Value of 16-bit elements.
+    pub fn from_u16s(type_: Atom, values: &[u16]) -> SelectionData {
+        let bytes = values.iter().flat_map(|value| value.to_ne_bytes()).collect();
+        SelectionData { type_, format: 16, bytes }
+    }
+
+    /// Size in bytes of the elements of the value.
+    pub(crate) fn width(&self) -> usize {
+        usize::from(self.format / 8)
+    }
+
+    /// Whether the value is made of whole elements of a supported format.
+    // `is_multiple_of` needs Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
+    pub(crate) fn check_format(&self) -> Result<(), Error> {
+        match self.format {
+            8 | 16 | 32 if self.bytes.len() % self.width() == 0 => Ok(()),
+            format => Err(Error::InvalidFormat(format))
+        }
+    }
+
+    /// The value as a list of atoms, such as the reply to `TARGETS`.
+    pub fn as_atoms(&self) -> Option<Vec<Atom>> {
+        self.as_u32s()
+    }
+
+    /// The value as 32-bit elements, such as `INTEGER`, `WINDOW` or `TIMESTAMP` values.
+    pub fn as_u32s(&self) -> Option<Vec<u32>> {
+        if self.format != 32 {
+            return None;
+        }
+        Some(self.bytes.chunks_exact(4)
+            .map(|chunk| u32::from_ne_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+            .collect())
+    }
+
+    /// The value as 16-bit elements.
+    pub fn as_u16s(&self) -> Option<Vec<u16>> {
+        if self.format != 16 {
+            return None;
+        }
+        Some(self.bytes.chunks_exact(2)
+            .map(|chunk| u16::from_ne_bytes([chunk[0], chunk[1]]))
+            .collect())
+    }
+
+    /// The value as text, when it is valid UTF-8.
+    pub fn as_str(&self) -> Option<&str> {
+        if self.format != 8 {
+            return None;
+        }
+        str::from_utf8(&self.bytes).ok()
+    }
+}
+
+/// What the owner of a selection tells about its value, see `Clipboard::load_with_hints`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Hints {
+    /// The value is a secret such as a password, clipboard managers should not keep it.
+    pub sensitive: bool,
+}
+
+impl From<SelectionData> for Vec<u8> {
+    fn from(data: SelectionData) -> Vec<u8> {
+        data.bytes
+    }
+}
@@ -0,0 +1,348 @@
+use std::io;
+use std::collections::{ BTreeMap, HashMap, HashSet, VecDeque };
+use std::os::fd::{ AsRawFd, RawFd };
+use std::sync::{ Condvar, Mutex, MutexGuard };
+use std::time::Duration;
+use x11rb::NONE;
+use x11rb::errors::ConnectionError;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::{ Atom, Property, Window };
+use error::Error;
+use run::{ create_pipe, poll_timeout, PipeDropFds };
+
+
+/// Routes the events of the getter window to the in-flight requests.
+///
+/// Every request gets its own property, so concurrent loads can tell
+/// their `SelectionNotify` and INCR `PropertyNotify` events apart.
+pub(crate) struct Dispatcher {
+    window: Window,
+    reader: Option<Reader>,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+/// The getter connection, when it is read by the waiting requests themselves.
+///
+/// One of them waits on the connection fd at a time, the others wait for
+/// their events to be dispatched. The wake pipe interrupts the reading one when
+/// another thread dispatched its events, since these may never show up on the fd.
+pub(crate) struct Reader {
+    stream_fd: RawFd,
+    wake: PipeDropFds,
+}
+
+/// The right to wait on the connection fd, given back on drop.
+pub(crate) struct Lead<'a> {
+    dispatcher: &'a Dispatcher,
+    reader: &'a Reader,
+}
+
+#[derive(Default)]
+struct State {
+    reading: bool,
+    next_id: u64,
+    slots: BTreeMap<u64, Slot>,
+    properties: HashSet<Atom>,
+    pool: Vec<Atom>,
+    pool_len: usize,
+    watched: HashMap<Atom, usize>,
+}
+
+struct Slot {
+    selection: Atom,
+    target: Atom,
+    property: Atom,
+    pooled: bool,
+    watch: Vec<Atom>,
+    sequence: u64,
+    events: VecDeque<(Event, Option<u64>)>,
+}
+
+/// An in-flight request, unregistered on drop.
+pub(crate) struct Request<'a> {
+    dispatcher: &'a Dispatcher,
+    id: u64,
+    pub(crate) selection: Atom,
+    pub(crate) property: Atom,
+}
+
+impl Dispatcher {
+    pub(crate) fn new(window: Window, reader: Option<Reader>) -> Dispatcher {
+        Dispatcher {
+            window, reader,
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, State>, Error> {
+        self.state.lock().map_err(|_| Error::Lock)
+    }
+
+    /// Become the thread reading the connection, unless another one already is.
+    pub(crate) fn lead(&self) -> Result<Option<Lead<'_>>, Error> {
+        let reader = match self.reader {
+            Some(ref reader) => reader,
+            None => return Ok(None)
+        };
+
+        let mut state = self.lock()?;
+        if state.reading {
+            return Ok(None);
+        }
+        state.reading = true;
+        Ok(Some(Lead { dispatcher: self, reader }))
+    }
+
+    /// Register a request before sending it.
+    ///
+    /// If `property` is already used by another request, a property is taken from a pool,
+    /// `intern` is called to create a new one when the pool is empty.
+    /// `watch` requests receive the XFIXES events of their selection.
+    pub(crate) fn register<F>(&self, selection: Atom, target: Atom, property: Atom, watch: bool, intern: F)
+        -> Result<Request<'_>, Error>
+        where F: FnOnce(usize) -> Result<Atom, Error>
+    {
+        let mut state = self.lock()?;
+
+        let (property, pooled) = if !state.properties.contains(&property) {
+            (property, false)
+        } else if let Some(property) = state.pool.pop() {
+            (property, true)
+        } else {
+            let property = intern(state.pool_len)?;
+            state.pool_len += 1;
+            (property, true)
+        };
+        state.properties.insert(property);
+
+        let watch = if watch { vec![selection] } else { Vec::new() };
+        Ok(self.insert(&mut state, Slot {
+            selection, target, property, pooled, watch,
+            sequence: 0,
+            events: VecDeque::new(),
+        }))
+    }
+
+    /// Register a request receiving the XFIXES events of several selections.
+    pub(crate) fn register_watch(&self, selections: Vec<Atom>) -> Result<Request<'_>, Error> {
+        let mut state = self.lock()?;
+        Ok(self.insert(&mut state, Slot {
+            selection: NONE,
+            target: NONE,
+            property: NONE,
+            pooled: false,
+            watch: selections,
+            sequence: 0,
+            events: VecDeque::new(),
+        }))
+    }
+
+    fn insert(&self, state: &mut State, slot: Slot) -> Request<'_> {
+        let id = state.next_id;
+        state.next_id += 1;
+        let (selection, property) = (slot.selection, slot.property);
+        state.slots.insert(id, slot);
+
+        Request { dispatcher: self, id, selection, property }
+    }
+
+    /// Count a watcher of `selection`, returns `true` for the first one.
+    pub(crate) fn watch(&self, selection: Atom) -> Result<bool, Error> {
+        let mut state = self.lock()?;
+        let count = state.watched.entry(selection).or_insert(0);
+        *count += 1;
+        Ok(*count == 1)
+    }
+
+    /// Remove a watcher of `selection`, returns `true` for the last one.
+    pub(crate) fn unwatch(&self, selection: Atom) -> Result<bool, Error> {
+        let mut state = self.lock()?;
+        let count = match state.watched.get_mut(&selection) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            },
+            None => return Ok(false)
+        };
+        if count == 0 {
+            state.watched.remove(&selection);
+        }
+        Ok(count == 0)
+    }
+
+    /// Route an event to the request waiting for it, returns `false` if there is none.
+    pub(crate) fn dispatch(&self, event: Event, sequence: Option<u64>) -> bool {
+        let mut state = match self.state.lock() {
+            Ok(state) => state,
+            Err(_) => return false
+        };
+
+        let mut routed = false;
+        for slot in state.slots.values_mut() {
+            let wanted = match event {
+                Event::SelectionNotify(ref event) => event.requestor == self.window
+                    && event.selection == slot.selection
+                    && (event.property == slot.property
+                        || (event.property == NONE && event.target == slot.target)),
+                Event::PropertyNotify(ref event) => event.window == self.window
+                    && event.state == Property::NEW_VALUE
+                    && event.atom == slot.property,
+                Event::XfixesSelectionNotify(ref event) => slot.watch.contains(&event.selection),
+                _ => false
+            };
+
+            if wanted {
+                slot.events.push_back((event.clone(), sequence));
+                routed = true;
+
+                // A refused conversion only answers one request
+                if let Event::SelectionNotify(_) = event { break };
+            }
+        }
+
+        if routed {
+            self.cond.notify_all();
+            if let (true, Some(reader)) = (state.reading, self.reader.as_ref()) {
+                reader.wake();
+            }
+        }
+        routed
+    }
+}
+
+impl<'a> Request<'a> {
+    /// Events sent before the request was made are ignored.
+    pub(crate) fn set_sequence(&self, sequence: u64) -> Result<(), Error> {
+        let mut state = self.dispatcher.lock()?;
+        if let Some(slot) = state.slots.get_mut(&self.id) {
+            slot.sequence = sequence;
+        }
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the next event of this request.
+    ///
+    /// When the connection is read by the waiting requests, this returns as soon as
+    /// nobody is reading it, so the caller can take the lead.
+    pub(crate) fn next(&self, timeout: Option<Duration>) -> Result<Option<Event>, Error> {
+        let id = self.id;
+        let cond = &self.dispatcher.cond;
+        let has_reader = self.dispatcher.reader.is_some();
+        let state = self.dispatcher.lock()?;
+        let waiting = |state: &mut State| (!has_reader || state.reading) && state.slots.get(&id)
+            .map(|slot| slot.events.is_empty())
+            .unwrap_or(false);
+        let mut state = match timeout {
+            Some(timeout) => cond.wait_timeout_while(state, timeout, waiting)
+                .map_err(|_| Error::Lock)?
+                .0,
+            None => cond.wait_while(state, waiting)
+                .map_err(|_| Error::Lock)?
+        };
+
+        let slot = match state.slots.get_mut(&id) {
+            Some(slot) => slot,
+            None => return Ok(None)
+        };
+        while let Some((event, sequence)) = slot.events.pop_front() {
+            match sequence {
+                Some(sequence) if sequence < slot.sequence => continue,
+                _ => return Ok(Some(event))
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl<'a> Drop for Request<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.dispatcher.state.lock() {
+            if let Some(slot) = state.slots.remove(&self.id) {
+                if slot.property != NONE {
+                    state.properties.remove(&slot.property);
+                }
+                if slot.pooled {
+                    state.pool.push(slot.property);
+                }
+            }
+
+            // The replies of this request may have queued events of the others
+            if let (true, Some(reader)) = (state.reading, self.dispatcher.reader.as_ref()) {
+                reader.wake();
+            }
+        }
+    }
+}
+
+impl Reader {
+    pub(crate) fn new(stream_fd: RawFd) -> Result<Reader, Error> {
+        let wake = create_pipe(libc::O_CLOEXEC | libc::O_NONBLOCK)?;
+        Ok(Reader { stream_fd, wake })
+    }
+
+    fn wake(&self) {
+        unsafe {
+            // Safety: Writing one byte from a valid buffer, the pipe is non-blocking,
+            // a full pipe already wakes the reader.
+            let byte = 1u8;
+            libc::write(self.wake.write_pipe.as_raw_fd(), &byte as *const u8 as *const libc::c_void, 1);
+        }
+    }
+
+    fn drain(&self) {
+        let mut buf = [0u8; 64];
+        loop {
+            let len = unsafe {
+                // Safety: Reading at most the length of the buffer into it, the pipe is non-blocking.
+                libc::read(self.wake.read_pipe.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len())
+            };
+            if len < buf.len() as isize {
+                break
+            }
+        }
+    }
+}
+
+impl<'a> Lead<'a> {
+    /// Wait up to `timeout` for the connection to be readable, or for a wake up.
+    pub(crate) fn poll(&self, timeout: Option<Duration>) -> Result<(), Error> {
+        let mut pollfds: [libc::pollfd; 2] = [libc::pollfd {
+            fd: self.reader.stream_fd,
+            events: libc::POLLIN,
+            revents: 0,
+        }, libc::pollfd {
+            fd: self.reader.wake.read_pipe.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        }];
+        let timeout = poll_timeout(timeout);
+
+        let poll_res = unsafe {
+            // Docs Linux: https://man7.org/linux/man-pages/man2/poll.2.html
+            // Safety: Passing in a mutable pointer that lives for the duration of the call, the length is
+            // set to the length of that pointer.
+            libc::poll(pollfds.as_mut_ptr(), pollfds.len() as libc::nfds_t, timeout)
+        };
+        if poll_res < 0 {
+            let err = io::Error::last_os_error();
+            if err.kind() != io::ErrorKind::Interrupted {
+                return Err(Error::XcbConnection(ConnectionError::IoError(err)));
+            }
+        }
+        if pollfds[1].revents & libc::POLLIN != 0 {
+            self.reader.drain();
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for Lead<'a> {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.dispatcher.state.lock() {
+            state.reading = false;
+        }
+        self.dispatcher.cond.notify_all();
+    }
+}
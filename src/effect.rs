@@ -0,0 +1,51 @@
+use std::fmt;
+use std::sync::Arc;
+use ::{ Atom, Window };
+
+
+/// Side effect asked through an ICCCM target, see `SideEffects`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SideEffect {
+    /// `DELETE`, remove the selected content.
+    Delete,
+    /// `INSERT_SELECTION`, replace the selected content with the `target` of another `selection`.
+    InsertSelection { selection: Atom, target: Atom },
+    /// `INSERT_PROPERTY`, replace the selected content with a value of the requestor.
+    InsertProperty { type_: Atom, value: Vec<u8> },
+}
+
+/// A side effect asked for one of the stored selections.
+#[derive(Clone, Debug)]
+pub struct SideEffectRequest {
+    pub requestor: Window,
+    pub selection: Atom,
+    pub effect: SideEffect,
+}
+
+/// Performs the side effects asked by requestors, see `ClipboardBuilder::side_effects`.
+///
+/// Returns whether it was performed, the request is answered with a zero-length `NULL` property if so,
+/// and with a `None` property otherwise.
+/// It runs while the request is answered, with the stored values locked. Calling `store`, `clear`, `status`
+/// or `shutdown` of the clipboard from it deadlocks, send the change to another thread instead.
+/// With a shared connection, it must not load a selection either.
+pub trait SideEffects: Send + Sync {
+    fn perform(&self, request: &SideEffectRequest) -> bool;
+}
+
+impl<F> SideEffects for F
+    where F: Fn(&SideEffectRequest) -> bool + Send + Sync
+{
+    fn perform(&self, request: &SideEffectRequest) -> bool {
+        self(request)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedEffects(pub(crate) Arc<dyn SideEffects>);
+
+impl fmt::Debug for SharedEffects {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SideEffects")
+    }
+}
@@ -19,6 +19,12 @@ pub enum Error {
     UnexpectedType(Atom),
     // Could change name on next major, since this uses pipes now.
     EventFdCreate,
+    Disconnected,
+    NoOwner,
+    ConversionRefused { target: Atom },
+    EmptyValues,
+    InvalidFormat(u8),
+    NoReads,
 }
 
 impl fmt::Display for Error {
@@ -35,6 +41,12 @@ impl fmt::Display for Error {
             Owner => write!(f, "Failed to set new owner of XCB selection"),
             UnexpectedType(target) => write!(f, "Unexpected Reply type: {:?}", target),
             EventFdCreate => write!(f, "Failed to create eventfd"),
+            Disconnected => write!(f, "XCB: Clipboard thread lost its connection"),
+            NoOwner => write!(f, "Selection has no owner"),
+            ConversionRefused { target } => write!(f, "Selection owner refused the conversion to {:?}", target),
+            EmptyValues => write!(f, "No value to store"),
+            InvalidFormat(format) => write!(f, "Value is not made of {}-bit elements", format),
+            NoReads => write!(f, "Value allows no read"),
         }
     }
 }
@@ -48,7 +60,8 @@ impl StdError for Error {
             XcbReply(e) => Some(e),
             XcbReplyOrId(e) => Some(e),
             XcbConnect(e) => Some(e),
-            Lock | Timeout | Owner | UnexpectedType(_) | EventFdCreate => None,
+            Lock | Timeout | Owner | UnexpectedType(_) | EventFdCreate | Disconnected
+                | NoOwner | ConversionRefused { .. } | EmptyValues | InvalidFormat(_) | NoReads => None,
         }
     }
 }
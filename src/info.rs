@@ -0,0 +1,190 @@
+use std::{ env, mem, process, ptr };
+use std::collections::VecDeque;
+use std::ffi::CStr;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::{ AtomEnum, ConnectionExt, GetPropertyReply };
+use ::{ Atom, Context, Window };
+use error::Error;
+
+
+/// Application owning a selection, see `Clipboard::owner_info`.
+#[derive(Clone, Debug)]
+pub struct OwnerInfo {
+    /// Window owning the selection.
+    pub owner: Window,
+    /// Top-level client window of the owner, the owner itself when it has none.
+    pub window: Window,
+    /// Instance name from `WM_CLASS`.
+    pub instance: Option<String>,
+    /// Class name from `WM_CLASS`.
+    pub class: Option<String>,
+    /// `_NET_WM_NAME`, or `WM_NAME`.
+    pub name: Option<String>,
+    /// `_NET_WM_PID`.
+    pub pid: Option<u32>,
+    /// `WM_CLIENT_MACHINE`.
+    pub machine: Option<String>,
+}
+
+/// A `SelectionRequest` answered by the clipboard, see `ClipboardBuilder::audit`.
+#[derive(Clone, Debug)]
+pub struct Access {
+    /// Window the value was sent to.
+    pub requestor: Window,
+    /// `_NET_WM_PID` of the requestor's client.
+    pub pid: Option<u32>,
+    /// Class name from the `WM_CLASS` of the requestor's client.
+    pub class: Option<String>,
+    pub selection: Atom,
+    pub target: Atom,
+    /// `NONE` when the request was refused.
+    pub property: Atom,
+    /// Size of the value sent.
+    pub bytes: usize,
+    /// Whether the value was sent with INCR.
+    pub incr: bool,
+}
+
+/// Targets describing the owner, answered for every stored selection, see `ClipboardBuilder::info_targets`.
+pub(crate) const INFO_TARGETS: &[&str] = &[
+    "LENGTH", "CLIENT_WINDOW", "HOSTNAME", "NAME", "USER", "PROCESS", "OWNER_OS"
+];
+
+/// This process, as described by the `INFO_TARGETS`.
+#[derive(Clone, Debug)]
+pub(crate) struct ProcessInfo {
+    pub(crate) hostname: Option<String>,
+    pub(crate) name: Option<String>,
+    pub(crate) user: Option<String>,
+    pub(crate) pid: u32,
+    pub(crate) os: Option<String>,
+}
+
+// Windows searched below a top-level window for its client
+const SEARCH_LIMIT: usize = 64;
+
+impl OwnerInfo {
+    pub(crate) fn query<C: Connection>(context: &Context<C>, owner: Window) -> Result<OwnerInfo, Error> {
+        let atoms = context.get_atoms(&["WM_STATE", "_NET_WM_NAME", "_NET_WM_PID"])?;
+        let (wm_state, net_wm_name, net_wm_pid) = (atoms[0], atoms[1], atoms[2]);
+        let connection = &*context.connection;
+
+        let has_state = |window: Window| -> Result<bool, Error> {
+            let reply = connection.get_property(false, window, wm_state, AtomEnum::ANY, 0, 0)?.reply()?;
+            Ok(reply.type_ != x11rb::NONE)
+        };
+
+        // Toolkits often own selections with a hidden window, look for the client above and below it
+        let mut window = owner;
+        let mut top = owner;
+        let client = loop {
+            if has_state(window)? {
+                break Some(window);
+            }
+            let tree = connection.query_tree(window)?.reply()?;
+            if tree.parent == tree.root || tree.parent == x11rb::NONE {
+                top = window;
+                break None;
+            }
+            window = tree.parent;
+        };
+        let client = match client {
+            Some(client) => client,
+            None => search(context, top, &has_state)?.unwrap_or(owner)
+        };
+
+        let get = |property: Atom| connection.get_property(false, client, property, AtomEnum::ANY, 0, u32::MAX);
+        let class = get(AtomEnum::WM_CLASS.into())?;
+        let net_name = get(net_wm_name)?;
+        let name = get(AtomEnum::WM_NAME.into())?;
+        let pid = get(net_wm_pid)?;
+        let machine = get(AtomEnum::WM_CLIENT_MACHINE.into())?;
+
+        let class = class.reply()?.value;
+        let mut class = class
+            .split(|&byte| byte == 0)
+            .map(|name| String::from_utf8_lossy(name).into_owned());
+        let name = match text(net_name.reply()?) {
+            Some(name) => Some(name),
+            None => text(name.reply()?)
+        };
+
+        Ok(OwnerInfo {
+            owner,
+            window: client,
+            instance: class.next().filter(|name| !name.is_empty()),
+            class: class.next().filter(|name| !name.is_empty()),
+            name,
+            pid: pid.reply()?.value32().and_then(|mut value| value.next()),
+            machine: text(machine.reply()?),
+        })
+    }
+}
+
+impl ProcessInfo {
+    pub(crate) fn current() -> ProcessInfo {
+        let (os, hostname) = uname().unzip();
+        let name = env::current_exe().ok()
+            .and_then(|path| path.file_name().map(|name| name.to_string_lossy().into_owned()));
+        let user = user().or_else(|| env::var("USER").ok());
+        ProcessInfo { hostname, name, user, pid: process::id(), os }
+    }
+}
+
+/// System and node names.
+fn uname() -> Option<(String, String)> {
+    unsafe {
+        // Safety: utsname is plain data, filled with nul-terminated strings on success
+        let mut uts: libc::utsname = mem::zeroed();
+        if libc::uname(&mut uts) < 0 {
+            return None;
+        }
+        let field = |field: &[libc::c_char]| CStr::from_ptr(field.as_ptr()).to_string_lossy().into_owned();
+        Some((field(&uts.sysname), field(&uts.nodename)))
+    }
+}
+
+/// Login name of the real user.
+fn user() -> Option<String> {
+    let mut buf = vec![0 as libc::c_char; 4096];
+    unsafe {
+        // Safety: getpwuid_r only writes to pwd and buf, within the given length,
+        // pw_name points into buf on success
+        let mut pwd: libc::passwd = mem::zeroed();
+        let mut result = ptr::null_mut();
+        let res = libc::getpwuid_r(libc::getuid(), &mut pwd, buf.as_mut_ptr(), buf.len(), &mut result);
+        if res != 0 || result.is_null() {
+            return None;
+        }
+        Some(CStr::from_ptr(pwd.pw_name).to_string_lossy().into_owned())
+    }
+}
+
+/// Breadth-first search of a window with `WM_STATE` below `top`.
+fn search<C, F>(context: &Context<C>, top: Window, has_state: &F) -> Result<Option<Window>, Error>
+    where C: Connection, F: Fn(Window) -> Result<bool, Error>
+{
+    let mut queue = VecDeque::new();
+    queue.push_back(top);
+    let mut searched = 0;
+
+    while let Some(window) = queue.pop_front() {
+        if searched == SEARCH_LIMIT {
+            break;
+        }
+        searched += 1;
+
+        if window != top && has_state(window)? {
+            return Ok(Some(window));
+        }
+        queue.extend(context.connection.query_tree(window)?.reply()?.children);
+    }
+    Ok(None)
+}
+
+fn text(reply: GetPropertyReply) -> Option<String> {
+    if reply.type_ == x11rb::NONE || reply.value.is_empty() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&reply.value).into_owned())
+}
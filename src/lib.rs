@@ -2,110 +2,296 @@ extern crate x11rb;
 extern crate libc;
 
 pub mod error;
+mod builder;
+mod dispatch;
 mod run;
+mod status;
+mod selection;
+mod cache;
+mod info;
+mod policy;
+mod effect;
+mod data;
+mod bridge;
+#[cfg(feature = "testing")]
+pub mod testing;
 
-pub use x11rb::protocol::xproto::{Atom, Window};
+pub use x11rb::protocol::xproto::{Atom, Timestamp, Window};
 pub use x11rb::rust_connection::RustConnection;
+pub use builder::ClipboardBuilder;
+pub use status::{ Health, OwnedSelection, Status, Transfer };
+pub use selection::{ Selection, SyncMode };
+pub use info::{ Access, OwnerInfo };
+pub use policy::{ AccessPolicy, AccessRequest };
+pub use effect::{ SideEffect, SideEffectRequest, SideEffects };
+pub use data::{ Hints, SelectionData };
+pub use bridge::Bridge;
+#[cfg(feature = "xcb")]
+pub use x11rb::xcb_ffi::XCBConnection;
 
-use std::thread;
+use std::cmp;
+use std::thread::{ self, JoinHandle };
 use std::time::{ Duration, Instant };
-use std::sync::{ Arc, RwLock };
+use std::sync::{ atomic, Arc, Mutex, RwLock };
+use std::sync::atomic::AtomicU32;
 use std::sync::mpsc::{ Sender, channel };
 use std::collections::HashMap;
-use std::os::fd::OwnedFd;
-use x11rb::connection::{Connection, RequestConnection};
+use std::os::fd::{ AsRawFd, OwnedFd };
+use x11rb::connection::Connection;
 use x11rb::{COPY_DEPTH_FROM_PARENT, CURRENT_TIME};
 use x11rb::errors::ConnectError;
 use x11rb::protocol::{Event, xfixes};
-use x11rb::protocol::xproto::{AtomEnum, ConnectionExt, CreateWindowAux, EventMask, Property, WindowClass};
+use x11rb::protocol::xproto::{AtomEnum, ChangeWindowAttributesAux, ConnectionExt, CreateWindowAux, EventMask, Property, PropMode, WindowClass};
 use error::Error;
-use run::{create_pipe_drop_fd, PipeDropFds};
+use builder::Config;
+use dispatch::{ Dispatcher, Reader, Request };
+use cache::AtomCache;
+use info::INFO_TARGETS;
+use run::{create_pipe_drop_fd, Command, Connect, Link, Owner, PipeDropFds, SharedHealth, Worker};
 
 pub const INCR_CHUNK_SIZE: usize = 4000;
 const POLL_DURATION: u64 = 50;
-type SetMap = Arc<RwLock<HashMap<Atom, (Atom, Vec<u8>)>>>;
+type SetMap = Arc<RwLock<HashMap<Atom, Entry>>>;
+type SharedSession<C> = Arc<RwLock<Arc<Session<C>>>>;
+
+/// Value of an owned selection.
+pub(crate) struct Entry {
+    /// Value for each target, the first one answers the targets missing from the list.
+    pub(crate) values: Vec<(Atom, SelectionData)>,
+    pub(crate) time: Timestamp,
+    /// Advertise `x-kde-passwordManagerHint`, so clipboard managers skip the value.
+    pub(crate) secret: bool,
+    /// When to give up the selection.
+    pub(crate) expires: Option<Instant>,
+    /// Complete transfers left before giving up the selection.
+    pub(crate) reads_left: Option<usize>,
+    /// Converts the value on demand, the results are added to `values`.
+    pub(crate) source: Option<Arc<dyn Source>>,
+    /// Targets of the source, once asked.
+    pub(crate) source_targets: Option<Vec<Atom>>,
+}
+
+/// Value converted on demand, when a requestor asks for it.
+pub(crate) trait Source: Send + Sync {
+    fn targets(&self) -> Result<Vec<Atom>, Error>;
+    fn convert(&self, target: Atom) -> Result<SelectionData, Error>;
+}
+
+impl Entry {
+    /// Entry owned until another client takes the selection, the time is set when storing it.
+    fn new(target: Atom, value: Vec<u8>) -> Entry {
+        Entry::with_values(vec![(target, SelectionData::new(target, value))])
+    }
+
+    fn with_values(values: Vec<(Atom, SelectionData)>) -> Entry {
+        Entry {
+            values,
+            time: CURRENT_TIME,
+            secret: false,
+            expires: None,
+            reads_left: None,
+            source: None,
+            source_targets: None,
+        }
+    }
+
+    /// Target and value answering a request for `target`.
+    ///
+    /// Without a source, the first value answers the targets missing from the list.
+    pub(crate) fn get(&self, target: Atom) -> Option<(Atom, &SelectionData)> {
+        let found = self.values.iter().find(|&&(value_target, _)| value_target == target);
+        let (target, ref value) = *match (found, &self.source) {
+            (Some(found), _) => found,
+            (None, &None) => self.values.first()?,
+            (None, &Some(_)) => return None
+        };
+        Some((target, value))
+    }
+}
+
+/// Targets marking a value as sensitive, such as a password.
+const SENSITIVE_TARGETS: &[&str] = &["x-kde-passwordManagerHint", "application/x-nspasteboard-concealed-type"];
+
+/// Times `load_with_hints` starts over when the selection changes meanwhile.
+const HINTS_ATTEMPTS: usize = 3;
+
+/// Targets which are not values, answered by the owner itself.
+const SPECIAL_TARGETS: &[&str] = &[
+    "TARGETS", "MULTIPLE", "TIMESTAMP", "SAVE_TARGETS", "DELETE", "INSERT_SELECTION", "INSERT_PROPERTY"
+];
+
+/// Whether a target names a value, rather than a special or informational target the owner answers itself.
+pub(crate) fn is_value_target(name: &str) -> bool {
+    !SPECIAL_TARGETS.contains(&name) && !INFO_TARGETS.contains(&name)
+}
+
+/// Timeout of the loads copying a selection, unless one is configured.
+const COPY_TIMEOUT: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug)]
 pub struct Atoms {
     pub primary: Atom,
+    pub secondary: Atom,
     pub clipboard: Atom,
     pub property: Atom,
     pub targets: Atom,
     pub string: Atom,
     pub utf8_string: Atom,
     pub incr: Atom,
+    pub password_manager_hint: Atom,
+    pub delete: Atom,
+    pub insert_selection: Atom,
+    pub insert_property: Atom,
+    pub null: Atom,
 }
 
 impl Atoms {
-    fn intern_all(conn: &RustConnection) -> Result<Atoms, Error> {
-        let clipboard = conn.intern_atom(
-            false,
-            b"CLIPBOARD",
-        )?;
-        let property = conn.intern_atom(
-            false,
-            b"THIS_CLIPBOARD_OUT",
-        )?;
-        let targets = conn.intern_atom(
-            false,
-            b"TARGETS",
-        )?;
-        let utf8_string = conn.intern_atom(
-            false,
-            b"UTF8_STRING",
-        )?;
-        let incr = conn.intern_atom(
-            false,
-            b"INCR",
-        )?;
+    fn intern_all<C: Connection>(conn: &C, cache: &AtomCache, property: &str) -> Result<Atoms, Error> {
+        let atoms = cache.intern_all(conn, &[
+            "CLIPBOARD", property, "TARGETS", "UTF8_STRING", "INCR", "x-kde-passwordManagerHint",
+            "DELETE", "INSERT_SELECTION", "INSERT_PROPERTY", "NULL"
+        ])?;
         Ok(Atoms {
             primary: Atom::from(AtomEnum::PRIMARY),
-            clipboard: clipboard.reply()?.atom,
-            property: property.reply()?.atom,
-            targets: targets.reply()?.atom,
+            secondary: Atom::from(AtomEnum::SECONDARY),
+            clipboard: atoms[0],
+            property: atoms[1],
+            targets: atoms[2],
             string: Atom::from(AtomEnum::STRING),
-            utf8_string: utf8_string.reply()?.atom,
-            incr: incr.reply()?.atom,
+            utf8_string: atoms[3],
+            incr: atoms[4],
+            password_manager_hint: atoms[5],
+            delete: atoms[6],
+            insert_selection: atoms[7],
+            insert_property: atoms[8],
+            null: atoms[9],
         })
     }
 }
 
 /// X11 Clipboard
-pub struct Clipboard {
-    pub getter: Context,
-    pub setter: Arc<Context>,
+pub struct Clipboard<C: Connection = RustConnection> {
+    /// Context the clipboard started with, see `Clipboard::context` for the current one.
+    pub getter: Context<C>,
+    /// Context the background thread started with, it uses a new one after reconnecting.
+    pub setter: Arc<Context<C>>,
     setmap: SetMap,
-    send: Sender<Atom>,
-    // Relying on the Drop in OwnedFd to close the fd
-    _drop_fd: OwnedFd,
+    config: Config,
+    session: SharedSession<C>,
+    backend: Backend<C>,
 }
 
-pub struct Context {
-    pub connection: RustConnection,
-    pub screen: usize,
-    pub window: Window,
-    pub atoms: Atoms
+/// Contexts in use, replaced by the background thread when it reconnects.
+pub(crate) struct Session<C: Connection> {
+    pub(crate) getter: Arc<Context<C>>,
+    pub(crate) setter: Arc<Context<C>>,
+    pub(crate) dispatcher: Dispatcher,
+}
+
+impl<C: Connection> Session<C> {
+    fn root(&self) -> Result<Window, Error> {
+        self.getter.connection.setup().roots.get(self.getter.screen)
+            .map(|screen| screen.root)
+            .ok_or(Error::XcbConnect(ConnectError::InvalidScreen))
+    }
+}
+
+enum Backend<C: Connection> {
+    /// Connections owned by the clipboard, served by a background thread.
+    Thread {
+        send: Sender<Command>,
+        // Relying on the Drop in OwnedFd to close the fd, also used to wake up the thread
+        drop_fd: OwnedFd,
+        health: SharedHealth,
+        handle: Option<JoinHandle<()>>,
+    },
+    /// Connection shared with the application, which forwards the events.
+    Shared {
+        owner: Mutex<Owner<C>>,
+        created_window: bool,
+        // Time of the latest forwarded event
+        last_time: AtomicU32,
+    },
 }
 
-#[inline]
-fn get_atom(connection: &RustConnection, name: &str) -> Result<Atom, Error> {
-    let intern_atom = connection.intern_atom(
-        false,
-        name.as_bytes()
-    )?;
-    let reply = intern_atom.reply()
-        .map_err(Error::XcbReply)?;
-    Ok(reply.atom)
+pub struct Context<C: Connection = RustConnection> {
+    pub connection: Arc<C>,
+    pub screen: usize,
+    pub window: Window,
+    pub atoms: Atoms,
+    cache: Arc<AtomCache>,
 }
 
 impl Context {
     pub fn new(displayname: Option<&str>) -> Result<Self, Error> {
-        let (connection, screen) = RustConnection::connect(displayname)?;
-        let window = connection.generate_id()?;
+        Context::connect(displayname, None, "THIS_CLIPBOARD_OUT", Arc::default())
+    }
 
-        {
-            let screen = connection.setup().roots.get(screen)
-                .ok_or(Error::XcbConnect(ConnectError::InvalidScreen))?;
+    fn connect(displayname: Option<&str>, screen: Option<usize>, property: &str, cache: Arc<AtomCache>)
+        -> Result<Self, Error>
+    {
+        let (connection, default_screen) = RustConnection::connect(displayname)?;
+        let screen = screen.unwrap_or(default_screen);
+        Context::attach(Arc::new(connection), screen, None, property, cache)
+    }
+}
+
+#[cfg(feature = "xcb")]
+impl Context<XCBConnection> {
+    fn connect_xcb(displayname: Option<&str>, screen: Option<usize>, property: &str, cache: Arc<AtomCache>)
+        -> Result<Self, Error>
+    {
+        use std::ffi::CString;
+        use x11rb::errors::DisplayParsingError;
+
+        let displayname = match displayname {
+            Some(name) => Some(CString::new(name)
+                .map_err(|_| ConnectError::DisplayParsingError(DisplayParsingError::MalformedValue(name.into())))?),
+            None => None
+        };
+        let (connection, default_screen) = XCBConnection::connect(displayname.as_deref())?;
+        let screen = screen.unwrap_or(default_screen);
+        Context::attach(Arc::new(connection), screen, None, property, cache)
+    }
+}
+
+impl<C: Connection> Clone for Context<C> {
+    fn clone(&self) -> Self {
+        Context {
+            connection: Arc::clone(&self.connection),
+            screen: self.screen,
+            window: self.window,
+            atoms: self.atoms.clone(),
+            cache: Arc::clone(&self.cache),
+        }
+    }
+}
+
+impl<C: Connection> Context<C> {
+    /// Create Context on an existing connection, optionally reusing one of its windows.
+    pub fn with_connection(connection: Arc<C>, screen: usize, window: Option<Window>) -> Result<Self, Error> {
+        Context::attach(connection, screen, window, "THIS_CLIPBOARD_OUT", Arc::default())
+    }
+
+    fn attach(connection: Arc<C>, screen: usize, window: Option<Window>, property: &str, cache: Arc<AtomCache>)
+        -> Result<Self, Error>
+    {
+        let root = connection.setup().roots.get(screen)
+            .ok_or(Error::XcbConnect(ConnectError::InvalidScreen))?;
+
+        let window = if let Some(window) = window {
+            // Keep the events selected by the application
+            let attributes = connection.get_window_attributes(window)?.reply()?;
+            connection.change_window_attributes(
+                window,
+                &ChangeWindowAttributesAux::new()
+                    .event_mask(attributes.your_event_mask | EventMask::PROPERTY_CHANGE)
+            )?
+                .check()?;
+            window
+        } else {
+            let window = connection.generate_id()?;
+            let screen = root;
             connection.create_window(
                 COPY_DEPTH_FROM_PARENT,
                 window,
@@ -121,15 +307,43 @@ impl Context {
                     .event_mask(EventMask::STRUCTURE_NOTIFY | EventMask::PROPERTY_CHANGE)
             )?
                 .check()?;
-        }
+            window
+        };
 
-        let atoms = Atoms::intern_all(&connection)?;
+        let atoms = Atoms::intern_all(&*connection, &cache, property)?;
 
-        Ok(Context { connection, screen, window, atoms })
+        Ok(Context { connection, screen, window, atoms, cache })
     }
 
+    /// Intern an atom, atoms and their names are cached.
     pub fn get_atom(&self, name: &str) -> Result<Atom, Error> {
-        get_atom(&self.connection, name)
+        Ok(self.cache.intern_all(&*self.connection, &[name])?[0])
+    }
+
+    /// Intern several atoms in a single round trip.
+    pub fn get_atoms<S: AsRef<str>>(&self, names: &[S]) -> Result<Vec<Atom>, Error> {
+        self.cache.intern_all(&*self.connection, names)
+    }
+
+    /// Name of an atom.
+    pub fn get_atom_name(&self, atom: Atom) -> Result<String, Error> {
+        Ok(self.cache.names(&*self.connection, &[atom])?.remove(0))
+    }
+
+    /// Names of several atoms in a single round trip, such as the reply to `TARGETS`.
+    pub fn get_atom_names(&self, atoms: &[Atom]) -> Result<Vec<String>, Error> {
+        self.cache.names(&*self.connection, atoms)
+    }
+
+    /// Atom of a selection, named selections are interned.
+    pub fn selection_atom(&self, selection: &Selection) -> Result<Atom, Error> {
+        match *selection {
+            Selection::Primary => Ok(self.atoms.primary),
+            Selection::Secondary => Ok(self.atoms.secondary),
+            Selection::Clipboard => Ok(self.atoms.clipboard),
+            Selection::Named(ref name) => self.get_atom(name),
+            Selection::Atom(atom) => Ok(atom),
+        }
     }
 }
 
@@ -137,63 +351,325 @@ impl Context {
 impl Clipboard {
     /// Create Clipboard.
     pub fn new() -> Result<Self, Error> {
-        let getter = Context::new(None)?;
-        let setter = Arc::new(Context::new(None)?);
-        let setter2 = Arc::clone(&setter);
+        ClipboardBuilder::new().build()
+    }
+
+    pub(crate) fn with_config(config: Config) -> Result<Self, Error> {
+        let (display, screen) = (config.display.clone(), config.screen);
+        let (property, atoms) = (config.property.clone(), config.atoms.clone());
+        let connect: Connect<RustConnection> = Box::new(move || {
+            // Atoms may differ on a restarted server, each connection starts with its own cache
+            let cache = Arc::new(AtomCache::default());
+            let getter = Context::connect(display.as_deref(), screen, &property, Arc::clone(&cache))?;
+            getter.get_atoms(&atoms)?;
+            let setter = Context::connect(display.as_deref(), screen, &property, cache)?;
+            Ok(Link {
+                getter_fd: getter.connection.stream().as_raw_fd(),
+                setter_fd: setter.connection.stream().as_raw_fd(),
+                getter, setter,
+            })
+        });
+        Clipboard::spawn(config, connect)
+    }
+}
+
+#[cfg(feature = "xcb")]
+impl Clipboard<XCBConnection> {
+    pub(crate) fn with_xcb_config(config: Config) -> Result<Self, Error> {
+        let (display, screen) = (config.display.clone(), config.screen);
+        let (property, atoms) = (config.property.clone(), config.atoms.clone());
+        let connect: Connect<XCBConnection> = Box::new(move || {
+            // Atoms may differ on a restarted server, each connection starts with its own cache
+            let cache = Arc::new(AtomCache::default());
+            let getter = Context::connect_xcb(display.as_deref(), screen, &property, Arc::clone(&cache))?;
+            getter.get_atoms(&atoms)?;
+            let setter = Context::connect_xcb(display.as_deref(), screen, &property, cache)?;
+            Ok(Link {
+                getter_fd: getter.connection.as_raw_fd(),
+                setter_fd: setter.connection.as_raw_fd(),
+                getter, setter,
+            })
+        });
+        Clipboard::spawn(config, connect)
+    }
+}
+
+impl<C: Connection + Send + Sync + 'static> Clipboard<C> {
+    /// Start the background thread.
+    ///
+    /// The contexts are opened with `connect`, which is kept to reconnect if enabled.
+    fn spawn(config: Config, connect: Connect<C>) -> Result<Self, Error> {
+        let Link { getter, getter_fd, setter, setter_fd } = connect()?;
+        let (getter, setter) = (Arc::new(getter), Arc::new(setter));
         let setmap = Arc::new(RwLock::new(HashMap::new()));
-        let setmap2 = Arc::clone(&setmap);
+        let owner = Clipboard::owner(&config, &setter, &setmap);
+        let health = Arc::new(Mutex::new(Health::Running));
+        let dispatcher = Dispatcher::new(getter.window, Some(Reader::new(getter_fd)?));
+        let session = Arc::new(RwLock::new(Arc::new(Session {
+            getter: Arc::clone(&getter),
+            setter: Arc::clone(&setter),
+            dispatcher,
+        })));
 
         let PipeDropFds {
             read_pipe, write_pipe
         } = create_pipe_drop_fd()?;
         let (sender, receiver) = channel();
-        let max_length = setter.connection.maximum_request_bytes();
-        thread::spawn(move || run::run(setter2, setmap2, max_length, receiver, read_pipe));
+        let worker = Worker {
+            owner, receiver, read_pipe,
+            stream_fd: setter_fd,
+            session: Arc::clone(&session),
+            health: Arc::clone(&health),
+            reconnect: if config.reconnect { Some(connect) } else { None },
+            shutdown: None,
+        };
+        let handle = thread::spawn(move || run::run(worker));
 
-        Ok(Clipboard { getter, setter, setmap, send: sender, _drop_fd: write_pipe })
+        let backend = Backend::Thread { send: sender, drop_fd: write_pipe, health, handle: Some(handle) };
+        Ok(Clipboard { getter: (*getter).clone(), setter, setmap, config, session, backend })
     }
+}
 
-    fn process_event<T>(&self, buff: &mut Vec<u8>, selection: Atom, target: Atom, property: Atom, timeout: T, use_xfixes: bool, sequence_number: u64)
-        -> Result<(), Error>
-        where T: Into<Option<Duration>>
+impl<C: Connection> Clipboard<C> {
+    /// Create Clipboard on a connection shared with the application.
+    ///
+    /// No connection or thread is created, the application must pass
+    /// its events to `handle_event_with_sequence`. See `ClipboardBuilder::build_with_connection`.
+    pub fn with_connection(connection: Arc<C>, screen: usize, window: Option<Window>) -> Result<Self, Error> {
+        ClipboardBuilder::new().build_with_connection(connection, screen, window)
+    }
+
+    pub(crate) fn with_shared(config: Config, connection: Arc<C>, screen: usize, window: Option<Window>)
+        -> Result<Self, Error>
     {
-        let mut is_incr = false;
-        let timeout = timeout.into();
-        let start_time =
-            if timeout.is_some() { Some(Instant::now()) }
-            else { None };
+        let created_window = window.is_none();
+        let getter = Context::attach(connection, screen, window, &config.property, Arc::default())?;
+        getter.get_atoms(&config.atoms)?;
+        let setter = Arc::new(getter.clone());
+        let setmap = Arc::new(RwLock::new(HashMap::new()));
+        let owner = Clipboard::owner(&config, &setter, &setmap);
 
+        let session = Arc::new(RwLock::new(Arc::new(Session {
+            getter: Arc::new(getter.clone()),
+            setter: Arc::clone(&setter),
+            dispatcher: Dispatcher::new(getter.window, None),
+        })));
+        let backend = Backend::Shared {
+            owner: Mutex::new(owner),
+            created_window,
+            last_time: AtomicU32::new(CURRENT_TIME),
+        };
+        Ok(Clipboard { getter, setter, setmap, config, session, backend })
+    }
+
+    fn owner(config: &Config, setter: &Arc<Context<C>>, setmap: &SetMap) -> Owner<C> {
+        Owner::new(Arc::clone(setter), Arc::clone(setmap), config)
+    }
+
+    /// Contexts in use.
+    fn session(&self) -> Result<Arc<Session<C>>, Error> {
+        Ok(Arc::clone(&*self.session.read().map_err(|_| Error::Lock)?))
+    }
+
+    /// Current getter context, a new one once the background thread reconnected.
+    ///
+    /// Atoms may change with it, when the server was restarted.
+    pub fn context(&self) -> Result<Arc<Context<C>>, Error> {
+        Ok(Arc::clone(&self.session()?.getter))
+    }
+
+    /// Fails if the background thread lost its connection.
+    fn check_health(&self) -> Result<(), Error> {
+        if let Backend::Thread { ref health, .. } = self.backend {
+            match *health.lock().map_err(|_| Error::Lock)? {
+                Health::Running => (),
+                Health::Reconnecting | Health::Disconnected | Health::Stopped => return Err(Error::Disconnected)
+            }
+        }
+        Ok(())
+    }
+
+    /// Run a command on the background thread and wait for its result.
+    fn command<R, F>(&self, make: F) -> Result<R, Error>
+        where F: FnOnce(Sender<Result<R, Error>>) -> Command
+    {
+        let (send, drop_fd) = match self.backend {
+            Backend::Thread { ref send, ref drop_fd, .. } => (send, drop_fd),
+            Backend::Shared { .. } => unreachable!("commands are only sent to the background thread")
+        };
+        let (reply, receiver) = channel();
+
+        send.send(make(reply)).map_err(|_| Error::Disconnected)?;
+        run::wake(drop_fd);
+
+        receiver.recv().map_err(|_| Error::Disconnected)?
+    }
+
+    /// Handle an event received on a shared connection.
+    ///
+    /// Returns `true` if the event was meant for the clipboard.
+    /// Loads wait for these events, so they must not be made from the thread forwarding them.
+    /// Without the sequence number, see `handle_event_with_sequence`, a late answer to a load
+    /// that timed out may be taken for the answer to the next one.
+    pub fn handle_event(&self, event: &Event) -> Result<bool, Error> {
+        self.handle_shared_event(event, None)
+    }
+
+    /// Handle an event received on a shared connection, with its sequence number
+    /// as given by `wait_for_event_with_sequence` or `poll_for_event_with_sequence`.
+    ///
+    /// Events older than the request a load is waiting for are ignored.
+    pub fn handle_event_with_sequence(&self, event: &Event, sequence: u64) -> Result<bool, Error> {
+        self.handle_shared_event(event, Some(sequence))
+    }
+
+    fn handle_shared_event(&self, event: &Event, sequence: Option<u64>) -> Result<bool, Error> {
+        let (owner, last_time) = match self.backend {
+            Backend::Shared { ref owner, ref last_time, .. } => (owner, last_time),
+            Backend::Thread { .. } => return Ok(false)
+        };
+
+        let time = match *event {
+            Event::KeyPress(ref event) | Event::KeyRelease(ref event) => event.time,
+            Event::ButtonPress(ref event) | Event::ButtonRelease(ref event) => event.time,
+            Event::MotionNotify(ref event) => event.time,
+            Event::EnterNotify(ref event) | Event::LeaveNotify(ref event) => event.time,
+            Event::PropertyNotify(ref event) => event.time,
+            _ => CURRENT_TIME
+        };
+        if time != CURRENT_TIME {
+            last_time.store(time, atomic::Ordering::Relaxed);
+        }
+
+        let handled = {
+            let mut owner = owner.lock().map_err(|_| Error::Lock)?;
+            owner.expire(Instant::now())?;
+            owner.handle_event(event)
+        };
+        let routed = self.session()?.dispatcher.dispatch(event.clone(), sequence);
+
+        Ok(handled || routed)
+    }
+
+    /// Register a request with the dispatcher, before sending it.
+    fn request<'s>(&self, session: &'s Session<C>, selection: Atom, target: Atom, property: Atom, watch: bool)
+        -> Result<Request<'s>, Error>
+    {
+        session.dispatcher.register(selection, target, property, watch, |n| {
+            session.getter.get_atom(&format!("{}_{}", self.config.property, n))
+        })
+    }
+
+    /// Get the current server time.
+    ///
+    /// Appends nothing to a property of the getter window and takes the time of the `PropertyNotify`.
+    /// On a shared connection, this could be called from the thread forwarding the events,
+    /// so the time of the latest forwarded event is used instead.
+    fn timestamp(&self, session: &Session<C>) -> Result<Timestamp, Error> {
+        if let Backend::Shared { ref last_time, .. } = self.backend {
+            return Ok(last_time.load(atomic::Ordering::Relaxed));
+        }
+
+        let property = session.getter.get_atom(&format!("{}_TIMESTAMP", self.config.property))?;
+        let request = self.request(session, x11rb::NONE, x11rb::NONE, property, false)?;
+
+        let cookie = session.getter.connection.change_property(
+            PropMode::APPEND,
+            session.getter.window,
+            request.property,
+            AtomEnum::INTEGER,
+            32,
+            0,
+            &[]
+        )?;
+        request.set_sequence(cookie.sequence_number())?;
+        cookie.check()?;
+
+        let deadline = Instant::now() + Duration::from_secs(1);
         loop {
-            if timeout.into_iter()
-                .zip(start_time)
-                .next()
-                .map(|(timeout, time)| (Instant::now() - time) >= timeout)
-                .unwrap_or(false)
-            {
-                return Err(Error::Timeout);
+            if let Event::PropertyNotify(event) = self.wait_event(session, &request, Some(deadline))? {
+                return Ok(event.time);
             }
+        }
+    }
 
-            let (event, seq) = match use_xfixes {
-                true => self.getter.connection.wait_for_event_with_sequence()?,
-                false => {
-                    match self.getter.connection.poll_for_event_with_sequence()? {
-                        Some(event) => event,
-                        None => {
-                            thread::park_timeout(Duration::from_millis(POLL_DURATION));
-                            continue
-                        }
+    /// Dispatch the events queued on the getter connection.
+    ///
+    /// Events of the shared connection are forwarded by the application,
+    /// otherwise the waiting threads read the getter connection for all of them.
+    fn drain(&self, session: &Session<C>) -> Result<(), Error> {
+        if let Backend::Thread { .. } = self.backend {
+            while let Some((event, seq)) = session.getter.connection.poll_for_event_with_sequence()? {
+                session.dispatcher.dispatch(event, Some(seq));
+            }
+        }
+        Ok(())
+    }
+
+    /// Run `f`, then dispatch the events read along with its replies on the getter connection.
+    ///
+    /// x11rb queues them without the fd becoming readable, so they would not wake up the lead.
+    pub(crate) fn draining<R, F>(&self, session: &Session<C>, f: F) -> Result<R, Error>
+        where F: FnOnce() -> Result<R, Error>
+    {
+        let result = f();
+        let drained = self.drain(session);
+        let value = result?;
+        drained.map(|()| value)
+    }
+
+    /// Wait for the next event of a request.
+    fn wait_event(&self, session: &Session<C>, request: &Request, deadline: Option<Instant>)
+        -> Result<Event, Error>
+    {
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Error::Timeout);
                     }
-                }
+                    Some(deadline - now)
+                },
+                None => None
             };
 
-            if seq < sequence_number {
-                continue;
+            self.drain(session)?;
+
+            if let Some(event) = request.next(Some(Duration::from_secs(0)))? {
+                return Ok(event);
+            }
+
+            match session.dispatcher.lead()? {
+                Some(lead) => lead.poll(timeout)?,
+                None => if let Some(event) = request.next(timeout)? {
+                    return Ok(event);
+                }
             }
+        }
+    }
+
+    fn process_event(&self, session: &Session<C>, request: &Request, target: Atom, timeout: Option<Duration>,
+                     use_xfixes: bool)
+        -> Result<SelectionData, Error>
+    {
+        let mut buff = Vec::new();
+        let (mut type_, mut format);
+        let mut is_incr = false;
+        let selection = request.selection;
+        let property = request.property;
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+
+        loop {
+            let event = self.wait_event(session, request, deadline)?;
 
             match event {
                 Event::XfixesSelectionNotify(event) if use_xfixes => {
-                    self.getter.connection.convert_selection(
-                        self.getter.window,
+                    // The selection was given up, wait for the next owner
+                    if event.owner == x11rb::NONE { continue };
+
+                    session.getter.connection.convert_selection(
+                        session.getter.window,
                         selection,
                         target,
                         property,
@@ -201,50 +677,50 @@ impl Clipboard {
                     )?.check()?;
                 }
                 Event::SelectionNotify(event) => {
-                    if event.selection != selection { continue };
-
                     // Note that setting the property argument to None indicates that the
                     // conversion requested could not be made.
                     if event.property == Atom::from(AtomEnum::NONE) {
-                        break;
+                        return Err(Error::ConversionRefused { target });
                     }
 
-                    let reply = self.getter.connection.get_property(
+                    let reply = session.getter.connection.get_property(
                         false,
-                        self.getter.window,
+                        session.getter.window,
                         event.property,
                         AtomEnum::NONE,
                         buff.len() as u32,
                         u32::MAX
                     )?.reply()?;
 
-                    if reply.type_ == self.getter.atoms.incr {
+                    if reply.type_ == session.getter.atoms.incr {
                         if let Some(mut value) = reply.value32() {
                             if let Some(size) = value.next() {
-                                buff.reserve(size as usize);
+                                // The size is only a lower bound, don't trust it with the memory
+                                let max = session.getter.connection.maximum_request_bytes();
+                                buff.reserve(cmp::min(size as usize, max));
                             }
                         }
-                        self.getter.connection.delete_property(
-                            self.getter.window,
+                        session.getter.connection.delete_property(
+                            session.getter.window,
                             property
                         )?.check()?;
                         is_incr = true;
                         continue
-                    } else if reply.type_ != target {
-                        return Err(Error::UnexpectedType(reply.type_));
                     }
 
                     buff.extend_from_slice(&reply.value);
+                    type_ = reply.type_;
+                    format = reply.format;
                     break
                 }
 
                 Event::PropertyNotify(event) if is_incr => {
-                    if event.state != Property::NEW_VALUE { continue };
+                    if event.state != Property::NEW_VALUE || event.atom != property { continue };
 
 
-                    let cookie = self.getter.connection.get_property(
+                    let cookie = session.getter.connection.get_property(
                         false,
-                        self.getter.window,
+                        session.getter.window,
                         property,
                         AtomEnum::NONE,
                         0,
@@ -253,16 +729,16 @@ impl Clipboard {
 
                     let length = cookie.reply()?.bytes_after;
 
-                    let cookie = self.getter.connection.get_property(
+                    let cookie = session.getter.connection.get_property(
                         true,
-                        self.getter.window,
+                        session.getter.window,
                         property,
                         AtomEnum::NONE,
                         0, length
                     )?;
                     let reply = cookie.reply()?;
-                    if reply.type_ != target { continue };
-
+                    type_ = reply.type_;
+                    format = reply.format;
                     let value = reply.value;
 
                     if !value.is_empty() {
@@ -274,104 +750,631 @@ impl Clipboard {
                 _ => ()
             }
         }
-        Ok(())
+        Ok(SelectionData { type_, format, bytes: buff })
     }
 
     /// load value.
-    pub fn load<T>(&self, selection: Atom, target: Atom, property: Atom, timeout: T)
-        -> Result<Vec<u8>, Error>
-        where T: Into<Option<Duration>>
+    ///
+    /// The value keeps the type the owner gave it, such as `INTEGER` for `TIMESTAMP` or `COMPOUND_TEXT` for `TEXT`.
+    /// Fails with `Error::NoOwner` when nobody owns the selection,
+    /// and with `Error::ConversionRefused` when the owner can't convert it to `target`.
+    pub fn load<S, T>(&self, selection: S, target: Atom, property: Atom, timeout: T)
+        -> Result<SelectionData, Error>
+        where S: Into<Selection>, T: Into<Option<Duration>>
     {
-        let mut buff = Vec::new();
-        let timeout = timeout.into();
+        self.check_health()?;
 
-        let cookie = self.getter.connection.convert_selection(
-            self.getter.window,
+        let session = self.session()?;
+        let timeout = timeout.into().or(self.config.timeout);
+        self.draining(&session, || self.convert(&session, selection.into(), target, property, timeout))
+    }
+
+    fn convert(&self, session: &Session<C>, selection: Selection, target: Atom, property: Atom,
+               timeout: Option<Duration>)
+        -> Result<SelectionData, Error>
+    {
+        let selection = session.getter.selection_atom(&selection)?;
+
+        // Nobody would answer, don't wait for the timeout
+        if session.getter.connection.get_selection_owner(selection)?.reply()?.owner == x11rb::NONE {
+            return Err(Error::NoOwner);
+        }
+        let request = self.request(session, selection, target, property, false)?;
+
+        let cookie = session.getter.connection.convert_selection(
+            session.getter.window,
             selection,
             target,
-            property,
+            request.property,
             CURRENT_TIME,
             // FIXME ^
             // Clients should not use CurrentTime for the time argument of a ConvertSelection request.
             // Instead, they should use the timestamp of the event that caused the request to be made.
         )?;
 
-        let sequence_number = cookie.sequence_number();
+        request.set_sequence(cookie.sequence_number())?;
         cookie.check()?;
 
-        self.process_event(&mut buff, selection, target, property, timeout, false, sequence_number)?;
+        let data = self.process_event(session, &request, target, timeout, false)?;
 
-        self.getter.connection.delete_property(
-            self.getter.window,
-            property
+        session.getter.connection.delete_property(
+            session.getter.window,
+            request.property
         )?.check()?;
 
-        Ok(buff)
+        Ok(data)
     }
 
     /// wait for a new value and load it
-    pub fn load_wait(&self, selection: Atom, target: Atom, property: Atom)
-        -> Result<Vec<u8>, Error>
+    ///
+    /// Without XFIXES, see `ClipboardBuilder::use_xfixes`, only a change of owner window is noticed.
+    pub fn load_wait<S: Into<Selection>>(&self, selection: S, target: Atom, property: Atom)
+        -> Result<SelectionData, Error>
     {
-        let mut buff = Vec::new();
+        self.check_health()?;
 
-        let screen = &self.getter.connection.setup().roots.get(self.getter.screen)
-            .ok_or(Error::XcbConnect(ConnectError::InvalidScreen))?;
+        let session = self.session()?;
+        self.draining(&session, || {
+            let selection = session.getter.selection_atom(&selection.into())?;
+            if !self.config.use_xfixes {
+                return self.poll_wait(&session, selection, target, property);
+            }
 
-        xfixes::query_version(
-            &self.getter.connection,
-            5,
-            0,
-        )?;
-        // Clear selection sources...
-        xfixes::select_selection_input(
-            &self.getter.connection,
-            screen.root,
-            self.getter.atoms.primary,
-            xfixes::SelectionEventMask::default()
-        )?;
-        xfixes::select_selection_input(
-            &self.getter.connection,
-            screen.root,
-            self.getter.atoms.clipboard,
-            xfixes::SelectionEventMask::default()
-        )?;
-        // ...and set the one requested now
-        let cookie = xfixes::select_selection_input(
-            &self.getter.connection,
-            screen.root,
-            selection,
-            xfixes::SelectionEventMask::SET_SELECTION_OWNER |
-                xfixes::SelectionEventMask::SELECTION_CLIENT_CLOSE |
-                xfixes::SelectionEventMask::SELECTION_WINDOW_DESTROY
-        )?;
+            let request = self.request(&session, selection, target, property, true)?;
+            let watch = Watch::new(&session, selection)?;
+            request.set_sequence(watch.sequence_number)?;
 
-        let sequence_number = cookie.sequence_number();
-        cookie.check()?;
+            let data = self.process_event(&session, &request, target, None, true)?;
+
+            session.getter.connection.delete_property(session.getter.window, request.property)?.check()?;
+
+            Ok(data)
+        })
+    }
+
+    /// wait for a new owner by polling, used when XFIXES is disabled
+    ///
+    /// There is no way to tell the same window taking the selection again, so that is missed.
+    fn poll_wait(&self, session: &Session<C>, selection: Atom, target: Atom, property: Atom)
+        -> Result<SelectionData, Error>
+    {
+        let mut owner = session.getter.connection.get_selection_owner(selection)?.reply()?.owner;
+
+        loop {
+            thread::park_timeout(self.config.poll_interval);
+
+            let new_owner = session.getter.connection.get_selection_owner(selection)?.reply()?.owner;
+            self.drain(session)?;
+            if new_owner != owner && new_owner != x11rb::NONE {
+                return self.load(selection, target, property, None);
+            }
+            owner = new_owner;
+        }
+    }
+
+    /// Targets a selection can be converted to.
+    pub fn targets<S, T>(&self, selection: S, timeout: T) -> Result<Vec<Atom>, Error>
+        where S: Into<Selection>, T: Into<Option<Duration>>
+    {
+        let atoms = self.session()?.getter.atoms.clone();
+        let data = self.load(selection, atoms.targets, atoms.property, timeout)?;
+        data.as_atoms().ok_or(Error::UnexpectedType(data.type_))
+    }
+
+    /// Whether the owner of a selection marks it as sensitive, such as a password.
+    ///
+    /// The selection may change between this and a `load`, `load_with_hints` checks the value it returns.
+    pub fn is_sensitive<S, T>(&self, selection: S, timeout: T) -> Result<bool, Error>
+        where S: Into<Selection>, T: Into<Option<Duration>>
+    {
+        let session = self.session()?;
+        self.draining(&session, || {
+            let selection = session.getter.selection_atom(&selection.into())?;
+            let timeout = timeout.into();
+            let targets = self.targets(selection, timeout)?;
+            self.marked_sensitive(&session, selection, &targets, session.getter.atoms.property, timeout)
+        })
+    }
+
+    /// Whether `targets` of a selection mark it as sensitive.
+    ///
+    /// A concealed type is enough, `x-kde-passwordManagerHint` must be converted to `secret`.
+    fn marked_sensitive(&self, session: &Session<C>, selection: Atom, targets: &[Atom], property: Atom,
+                        timeout: Option<Duration>)
+        -> Result<bool, Error>
+    {
+        let sensitive = session.getter.get_atoms(SENSITIVE_TARGETS)?;
+        if targets.contains(&sensitive[1]) {
+            return Ok(true);
+        }
+        if !targets.contains(&sensitive[0]) {
+            return Ok(false);
+        }
+        match self.load(selection, sensitive[0], property, timeout) {
+            Ok(data) => Ok(data.bytes == b"secret"),
+            Err(Error::ConversionRefused { .. }) | Err(Error::UnexpectedType(_)) => Ok(false),
+            Err(err) => Err(err)
+        }
+    }
 
-        self.process_event(&mut buff, selection, target, property, None, true, sequence_number)?;
+    /// Load a value along with the hints of its owner, such as whether it is sensitive.
+    ///
+    /// The hints take a few more conversions. When the selection changes meanwhile, everything is loaded again,
+    /// and `Error::Timeout` is returned if it keeps changing.
+    pub fn load_with_hints<S, T>(&self, selection: S, target: Atom, property: Atom, timeout: T)
+        -> Result<(SelectionData, Hints), Error>
+        where S: Into<Selection>, T: Into<Option<Duration>>
+    {
+        let session = self.session()?;
+        let timeout = timeout.into().or(self.config.timeout);
+        self.draining(&session, || {
+            let selection = session.getter.selection_atom(&selection.into())?;
+            for _ in 0..HINTS_ATTEMPTS {
+                let before = self.ownership(&session, selection, property, timeout)?;
+                let targets = self.load(selection, session.getter.atoms.targets, property, timeout)?;
+                let targets = targets.as_atoms().ok_or(Error::UnexpectedType(targets.type_))?;
+                let sensitive = self.marked_sensitive(&session, selection, &targets, property, timeout)?;
+                let data = self.load(selection, target, property, timeout)?;
 
-        self.getter.connection.delete_property(self.getter.window, property)?.check()?;
+                if self.ownership(&session, selection, property, timeout)? == before {
+                    return Ok((data, Hints { sensitive }));
+                }
+            }
+            Err(Error::Timeout)
+        })
+    }
 
-        Ok(buff)
+    /// Wait for a new value, like `load_wait`, and load it along with the hints of its new owner.
+    ///
+    /// The new owner is noticed through its `TARGETS`, the value is then loaded as by `load_with_hints`.
+    pub fn load_wait_with_hints<S: Into<Selection>>(&self, selection: S, target: Atom, property: Atom)
+        -> Result<(SelectionData, Hints), Error>
+    {
+        let session = self.session()?;
+        let selection = session.getter.selection_atom(&selection.into())?;
+        self.load_wait(selection, session.getter.atoms.targets, property)?;
+        self.load_with_hints(selection, target, property, None)
+    }
+
+    /// Owner window of a selection, and when it took the selection if it answers `TIMESTAMP`.
+    ///
+    /// The time tells a new value of the same window apart.
+    fn ownership(&self, session: &Session<C>, selection: Atom, property: Atom, timeout: Option<Duration>)
+        -> Result<(Window, Option<Timestamp>), Error>
+    {
+        let owner = session.getter.connection.get_selection_owner(selection)?.reply()?.owner;
+        if owner == x11rb::NONE {
+            return Err(Error::NoOwner);
+        }
+        let timestamp = session.getter.get_atom("TIMESTAMP")?;
+        let time = match self.load(selection, timestamp, property, timeout) {
+            Ok(data) => data.as_u32s().and_then(|times| times.first().cloned()),
+            Err(Error::ConversionRefused { .. }) | Err(Error::UnexpectedType(_)) => None,
+            Err(err) => return Err(err)
+        };
+        Ok((owner, time))
     }
 
     /// store value.
-    pub fn store<T: Into<Vec<u8>>>(&self, selection: Atom, target: Atom, value: T)
+    pub fn store<S, T>(&self, selection: S, target: Atom, value: T)
+        -> Result<(), Error>
+        where S: Into<Selection>, T: Into<Vec<u8>>
+    {
+        self.store_entry(selection.into(), Entry::new(target, value.into()))
+    }
+
+    /// Store a value with its own type and format, such as a list of `ATOM`s or an `INTEGER`.
+    ///
+    /// Fails with `Error::InvalidFormat` unless the value is made of whole 8, 16 or 32-bit elements.
+    pub fn store_data<S: Into<Selection>>(&self, selection: S, target: Atom, data: SelectionData)
+        -> Result<(), Error>
+    {
+        data.check_format()?;
+        self.store_entry(selection.into(), Entry::with_values(vec![(target, data)]))
+    }
+
+    /// Store a value converted to several targets, the first one answers the requests for other targets.
+    pub fn store_targets<S: Into<Selection>>(&self, selection: S, values: Vec<(Atom, Vec<u8>)>)
+        -> Result<(), Error>
+    {
+        if values.is_empty() {
+            return Err(Error::EmptyValues);
+        }
+        let values = values.into_iter()
+            .map(|(target, value)| (target, SelectionData::new(target, value)))
+            .collect();
+        self.store_entry(selection.into(), Entry::with_values(values))
+    }
+
+    /// Store a value converted by `source` when it is requested.
+    pub(crate) fn store_source(&self, selection: Atom, source: Arc<dyn Source>) -> Result<(), Error> {
+        let mut entry = Entry::with_values(Vec::new());
+        entry.source = Some(source);
+        self.store_entry(Selection::Atom(selection), entry)
+    }
+
+    /// Store a secret, such as a password.
+    ///
+    /// It is marked with `x-kde-passwordManagerHint`, so clipboard managers don't keep it.
+    pub fn store_secret<S, T>(&self, selection: S, target: Atom, value: T)
+        -> Result<(), Error>
+        where S: Into<Selection>, T: Into<Vec<u8>>
+    {
+        let mut entry = Entry::new(target, value.into());
+        entry.secret = true;
+        self.store_entry(selection.into(), entry)
+    }
+
+    /// Store a value for `ttl`, then give up the selection and wipe the value.
+    ///
+    /// On a shared connection, this happens on the first event forwarded to `handle_event` after `ttl`.
+    pub fn store_with_ttl<S, T>(&self, selection: S, target: Atom, value: T, ttl: Duration)
+        -> Result<(), Error>
+        where S: Into<Selection>, T: Into<Vec<u8>>
+    {
+        let mut entry = Entry::new(target, value.into());
+        entry.expires = Some(Instant::now() + ttl);
+        self.store_entry(selection.into(), entry)
+    }
+
+    /// Store a value for `max_reads` complete transfers, then give up the selection and wipe the value.
+    ///
+    /// `TARGETS` requests are not counted. Fails with `Error::NoReads` when `max_reads` is 0.
+    pub fn store_limited<S, T>(&self, selection: S, target: Atom, value: T, max_reads: usize)
+        -> Result<(), Error>
+        where S: Into<Selection>, T: Into<Vec<u8>>
+    {
+        if max_reads == 0 {
+            return Err(Error::NoReads);
+        }
+        let mut entry = Entry::new(target, value.into());
+        entry.reads_left = Some(max_reads);
+        self.store_entry(selection.into(), entry)
+    }
+
+    /// Take ownership of a selection with `entry`, at the current server time.
+    fn store_entry(&self, selection: Selection, mut entry: Entry) -> Result<(), Error> {
+        self.check_health()?;
+
+        let session = self.session()?;
+        let selection = session.getter.selection_atom(&selection)?;
+        entry.time = self.timestamp(&session)?;
+        match self.backend {
+            Backend::Thread { .. } => self.command(|reply| Command::Store { selection, entry, reply }),
+            Backend::Shared { ref owner, .. } => owner.lock()
+                .map_err(|_| Error::Lock)?
+                .store(selection, entry)
+        }
+    }
+
+    /// Give up the ownership of a selection.
+    ///
+    /// This also works when the selection is owned by another client, which loses it.
+    pub fn clear<S: Into<Selection>>(&self, selection: S) -> Result<(), Error> {
+        self.check_health()?;
+
+        let session = self.session()?;
+        let selection = session.getter.selection_atom(&selection.into())?;
+        let time = self.timestamp(&session)?;
+        self.clear_at(selection, time)
+    }
+
+    /// Give up a held selection at the time it was stored, so it stays with a client that took it since.
+    pub(crate) fn clear_stored(&self, selection: Atom) -> Result<(), Error> {
+        self.check_health()?;
+
+        let time = match self.setmap.read().map_err(|_| Error::Lock)?.get(&selection) {
+            Some(entry) => entry.time,
+            None => return Ok(())
+        };
+        self.clear_at(selection, time)
+    }
+
+    fn clear_at(&self, selection: Atom, time: Timestamp) -> Result<(), Error> {
+        match self.backend {
+            Backend::Thread { .. } => self.command(|reply| Command::Clear { selection, time, reply }),
+            Backend::Shared { ref owner, .. } => owner.lock()
+                .map_err(|_| Error::Lock)?
+                .clear(selection, time)
+        }
+    }
+
+    /// Keep PRIMARY and CLIPBOARD in sync, until an error occurs.
+    ///
+    /// When a selection gets a new owner, its value is loaded for all its targets and stored in the other one.
+    /// PRIMARY changes quickly while text is being selected, so it is copied once it did not change for `debounce`.
+    /// Sensitive values are not copied, nor are the selections owned by this clipboard, such as its own copies.
+    pub fn sync(&self, mode: SyncMode, debounce: Duration) -> Result<(), Error> {
+        let atoms = self.session()?.getter.atoms.clone();
+        self.sync_selections(atoms.primary, atoms.clipboard, mode, debounce)
+    }
+
+    /// `sync` between two selections, standing for PRIMARY and CLIPBOARD.
+    pub(crate) fn sync_selections(&self, primary: Atom, clipboard: Atom, mode: SyncMode, debounce: Duration)
         -> Result<(), Error>
     {
-        self.send.send(selection)?;
-        self.setmap
-            .write()
+        self.check_health()?;
+
+        let session = self.session()?;
+        let sources = match mode {
+            SyncMode::PrimaryToClipboard => vec![primary],
+            SyncMode::ClipboardToPrimary => vec![clipboard],
+            SyncMode::Both => vec![primary, clipboard],
+        };
+
+        let request = session.dispatcher.register_watch(sources.clone())?;
+        let watches = sources.iter()
+            .map(|&selection| Watch::new(&session, selection))
+            .collect::<Result<Vec<_>, _>>()?;
+        if let Some(watch) = watches.last() {
+            request.set_sequence(watch.sequence_number)?;
+        }
+
+        // Selection to copy, and when
+        let mut pending: Option<(Atom, Instant)> = None;
+        loop {
+            let event = match self.wait_event(&session, &request, pending.map(|(_, deadline)| deadline)) {
+                Ok(event) => event,
+                Err(Error::Timeout) => {
+                    if let Some((source, _)) = pending.take() {
+                        let destination = if source == primary { clipboard } else { primary };
+                        self.copy(source, destination)?;
+                    }
+                    continue
+                },
+                Err(err) => return Err(err)
+            };
+
+            if let Event::XfixesSelectionNotify(event) = event {
+                if event.owner == x11rb::NONE || event.owner == session.setter.window { continue };
+
+                let delay = if event.selection == primary { debounce } else { Duration::from_secs(0) };
+                pending = Some((event.selection, Instant::now() + delay));
+            }
+        }
+    }
+
+    /// Copy the value of `source` for all its targets to `destination`.
+    ///
+    /// Failures caused by the owner of `source` only skip the copy.
+    fn copy(&self, source: Atom, destination: Atom) -> Result<(), Error> {
+        let skipped = |err: &Error| matches!(*err,
+            Error::NoOwner | Error::Owner | Error::ConversionRefused { .. } | Error::Timeout | Error::UnexpectedType(_));
+        let timeout = self.config.timeout.unwrap_or(COPY_TIMEOUT);
+        let session = self.session()?;
+
+        let targets = match self.targets(source, timeout) {
+            Ok(targets) => targets,
+            Err(ref err) if skipped(err) => return Ok(()),
+            Err(err) => return Err(err)
+        };
+        match self.marked_sensitive(&session, source, &targets, session.getter.atoms.property, Some(timeout)) {
+            Ok(false) => (),
+            Ok(true) => return Ok(()),
+            Err(ref err) if skipped(err) => return Ok(()),
+            Err(err) => return Err(err)
+        }
+
+        let names = self.draining(&session, || session.getter.get_atom_names(&targets))?;
+        let mut values = Vec::new();
+        for (target, _) in targets.into_iter().zip(names).filter(|(_, name)| is_value_target(name)) {
+            match self.load(source, target, session.getter.atoms.property, timeout) {
+                Ok(data) => if data.check_format().is_ok() {
+                    values.push((target, data))
+                },
+                Err(ref err) if skipped(err) => (),
+                Err(err) => return Err(err)
+            }
+        }
+        if values.is_empty() {
+            return Ok(());
+        }
+
+        // Keeping the type and format of each value
+        match self.store_entry(Selection::Atom(destination), Entry::with_values(values)) {
+            Err(ref err) if skipped(err) => Ok(()),
+            result => result
+        }
+    }
+
+    /// Identify the application owning a selection, from the properties of its top-level client window.
+    pub fn owner_info<S: Into<Selection>>(&self, selection: S) -> Result<OwnerInfo, Error> {
+        let session = self.session()?;
+        self.draining(&session, || {
+            let selection = session.getter.selection_atom(&selection.into())?;
+            let owner = session.getter.connection.get_selection_owner(selection)?.reply()?.owner;
+            if owner == x11rb::NONE {
+                return Err(Error::NoOwner);
+            }
+            OwnerInfo::query(&session.getter, owner)
+        })
+    }
+
+    /// Stop serving the selections once the INCR transfers in progress are done.
+    ///
+    /// New requests are refused meanwhile. The transfers still running at `deadline`
+    /// are aborted and `Error::Timeout` is returned, the background thread is joined in any case.
+    /// On a shared connection, the application must keep forwarding its events until this returns.
+    pub fn shutdown(mut self, deadline: Instant) -> Result<(), Error> {
+        let result = match self.backend {
+            Backend::Thread { .. } => self.command(|reply| Command::Shutdown { deadline, reply }),
+            Backend::Shared { ref owner, .. } => {
+                owner.lock().map_err(|_| Error::Lock)?.close();
+                loop {
+                    {
+                        let mut owner = owner.lock().map_err(|_| Error::Lock)?;
+                        if owner.is_idle() {
+                            break Ok(());
+                        }
+                        if Instant::now() >= deadline {
+                            owner.abort();
+                            break Err(Error::Timeout);
+                        }
+                    }
+                    thread::park_timeout(self.config.poll_interval);
+                }
+            }
+        };
+
+        // The thread exits after replying, or already has
+        if let Backend::Thread { ref mut handle, .. } = self.backend {
+            if let Some(handle) = handle.take() {
+                let _ = handle.join();
+            }
+        }
+        result
+    }
+
+    /// Report the state of the background thread, the owned selections and the INCR transfers in progress.
+    pub fn status(&self) -> Result<Status, Error> {
+        let selections = self.setmap
+            .read()
             .map_err(|_| Error::Lock)?
-            .insert(selection, (target, value.into()));
+            .iter()
+            .map(|(&selection, entry)| OwnedSelection {
+                selection,
+                target: entry.values.first().map(|&(target, _)| target).unwrap_or(x11rb::NONE),
+                size: entry.values.iter().map(|(_, data)| data.bytes.len()).sum(),
+                timestamp: entry.time,
+            })
+            .collect();
 
-        self.setter.connection.set_selection_owner(
-            self.setter.window,
-            selection,
-            CURRENT_TIME
-        )?.check()?;
+        let (health, transfers) = match self.backend {
+            Backend::Thread { ref health, .. } => {
+                let health = *health.lock().map_err(|_| Error::Lock)?;
+                let transfers = match health {
+                    Health::Running => self.command(|reply| Command::Transfers { reply })?,
+                    _ => Vec::new()
+                };
+                (health, transfers)
+            },
+            Backend::Shared { ref owner, .. } => {
+                let transfers = owner.lock()
+                    .map_err(|_| Error::Lock)?
+                    .transfers();
+                (Health::Running, transfers)
+            }
+        };
 
-        Ok(())
+        Ok(Status { health, selections, transfers })
+    }
+}
+
+impl<C: Connection> Drop for Clipboard<C> {
+    fn drop(&mut self) {
+        if let Backend::Shared { created_window: true, .. } = self.backend {
+            let _ = self.getter.connection.destroy_window(self.getter.window);
+            let _ = self.getter.connection.flush();
+        }
+    }
+}
+
+/// XFIXES selection input, shared by all watchers of a selection.
+struct Watch<'a, C: Connection> {
+    session: &'a Session<C>,
+    selection: Atom,
+    sequence_number: u64,
+}
+
+impl<'a, C: Connection> Watch<'a, C> {
+    fn new(session: &'a Session<C>, selection: Atom) -> Result<Self, Error> {
+        let connection = &*session.getter.connection;
+        let first = session.dispatcher.watch(selection)?;
+        let mut watch = Watch { session, selection, sequence_number: 0 };
+
+        xfixes::query_version(connection, 5, 0)?;
+
+        // Already selected, only the events after this request are relevant
+        watch.sequence_number = if first {
+            let cookie = xfixes::select_selection_input(
+                connection,
+                session.root()?,
+                selection,
+                xfixes::SelectionEventMask::SET_SELECTION_OWNER |
+                    xfixes::SelectionEventMask::SELECTION_CLIENT_CLOSE |
+                    xfixes::SelectionEventMask::SELECTION_WINDOW_DESTROY
+            )?;
+            let sequence_number = cookie.sequence_number();
+            cookie.check()?;
+            sequence_number
+        } else {
+            connection.get_input_focus()?.sequence_number()
+        };
+
+        Ok(watch)
+    }
+}
+
+impl<'a, C: Connection> Drop for Watch<'a, C> {
+    fn drop(&mut self) {
+        if let (Ok(true), Ok(root)) = (self.session.dispatcher.unwatch(self.selection), self.session.root()) {
+            let _ = xfixes::select_selection_input(
+                &*self.session.getter.connection,
+                root,
+                self.selection,
+                xfixes::SelectionEventMask::default()
+            );
+            let _ = self.session.getter.connection.flush();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+    use x11rb::protocol::xproto::ConnectionExt;
+    use ::{ Clipboard, SyncMode };
+
+    /// Between two other selections, the real PRIMARY and CLIPBOARD are left alone.
+    #[test]
+    fn sync() {
+        let syncer = Arc::new(Clipboard::new().unwrap());
+        let other = Clipboard::new().unwrap();
+
+        let atom_primary = other.setter.get_atom("X11_CLIPBOARD_TEST_SYNC_PRIMARY").unwrap();
+        let atom_clipboard = other.setter.get_atom("X11_CLIPBOARD_TEST_SYNC_CLIPBOARD").unwrap();
+        let atom_utf8string = other.setter.atoms.utf8_string;
+        let atom_property = other.getter.atoms.property;
+        let dur = Duration::from_secs(3);
+
+        {
+            let syncer = Arc::clone(&syncer);
+            thread::spawn(move || {
+                let _ = syncer.sync_selections(atom_primary, atom_clipboard, SyncMode::Both, Duration::from_millis(500));
+            });
+        }
+        thread::sleep(Duration::from_millis(200));
+
+        // PRIMARY is only copied once it stopped changing
+        other.store(atom_primary, atom_utf8string, "first").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        other.store(atom_primary, atom_utf8string, "second").unwrap();
+        thread::sleep(Duration::from_millis(200));
+        let owner = other.getter.connection.get_selection_owner(atom_clipboard).unwrap().reply().unwrap().owner;
+        assert_eq!(owner, x11rb::NONE);
+
+        thread::sleep(Duration::from_millis(600));
+        let output = other.load(atom_clipboard, atom_utf8string, atom_property, dur).unwrap();
+        assert_eq!(output.bytes, b"second");
+
+        // Its own copy to CLIPBOARD is not copied back to PRIMARY
+        thread::sleep(Duration::from_millis(300));
+        let owner = other.getter.connection.get_selection_owner(atom_primary).unwrap().reply().unwrap().owner;
+        assert_eq!(owner, other.setter.window);
+
+        // Nor are secrets
+        other.store_secret(atom_clipboard, atom_utf8string, "hunter2").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        let output = other.load(atom_primary, atom_utf8string, atom_property, dur).unwrap();
+        assert_eq!(output.bytes, b"second");
+
+        // CLIPBOARD is copied right away
+        other.store(atom_clipboard, atom_utf8string, "third").unwrap();
+        thread::sleep(Duration::from_millis(300));
+        let output = other.load(atom_primary, atom_utf8string, atom_property, dur).unwrap();
+        assert_eq!(output.bytes, b"third");
     }
 }
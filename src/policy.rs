@@ -0,0 +1,47 @@
+use std::fmt;
+use std::sync::Arc;
+use ::{ Atom, Window };
+
+
+/// A request for a stored value, see `AccessPolicy`.
+#[derive(Clone, Debug)]
+pub struct AccessRequest {
+    /// Window the value would be sent to.
+    pub requestor: Window,
+    /// `_NET_WM_PID` of the requestor's client.
+    pub pid: Option<u32>,
+    /// Instance name from the `WM_CLASS` of the requestor's client.
+    pub instance: Option<String>,
+    /// Class name from the `WM_CLASS` of the requestor's client.
+    pub class: Option<String>,
+    pub selection: Atom,
+    pub target: Atom,
+    /// Whether the value was stored with `store_secret`.
+    pub secret: bool,
+}
+
+/// Decides which requestors are served, see `ClipboardBuilder::access_policy`.
+///
+/// Denied requests are answered with a `None` property.
+/// It is asked while the request is being answered, with the stored values locked,
+/// so calling `store`, `clear`, `status` or `shutdown` of the clipboard from it deadlocks.
+pub trait AccessPolicy: Send + Sync {
+    fn allow(&self, request: &AccessRequest) -> bool;
+}
+
+impl<F> AccessPolicy for F
+    where F: Fn(&AccessRequest) -> bool + Send + Sync
+{
+    fn allow(&self, request: &AccessRequest) -> bool {
+        self(request)
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct SharedPolicy(pub(crate) Arc<dyn AccessPolicy>);
+
+impl fmt::Debug for SharedPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("AccessPolicy")
+    }
+}
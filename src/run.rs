@@ -1,26 +1,43 @@
-use std::cmp;
-use std::sync::Arc;
-use std::sync::mpsc::{ Receiver, TryRecvError };
+use std::{ cmp, mem, ptr };
+use std::time::{ Duration, Instant };
+use std::sync::{ Arc, Mutex };
+use std::sync::mpsc::{ Receiver, Sender, TryRecvError };
 use std::collections::HashMap;
-use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd, RawFd};
 use ::{AtomEnum, EventMask};
 use x11rb::connection::Connection;
 use x11rb::protocol::Event;
-use x11rb::protocol::xproto::{Atom, ChangeWindowAttributesAux, ConnectionExt, Property, PropMode, SELECTION_NOTIFY_EVENT, SelectionNotifyEvent, Window};
-use ::{ INCR_CHUNK_SIZE, Context, SetMap };
+use x11rb::protocol::xproto::{Atom, ChangeWindowAttributesAux, ConnectionExt, Property, PropMode, SELECTION_NOTIFY_EVENT, SelectionNotifyEvent, SelectionRequestEvent, Window};
+use ::{ Context, Entry, SelectionData, Session, SetMap, SharedSession, Timestamp };
+use status::{ Health, Transfer };
+use info::{ Access, OwnerInfo, ProcessInfo, INFO_TARGETS };
+use builder::Config;
+use policy::{ AccessRequest, SharedPolicy };
+use effect::{ SharedEffects, SideEffect, SideEffectRequest };
+use dispatch::{ Dispatcher, Reader };
 use error::Error;
 
-macro_rules! try_continue {
+const RECONNECT_MIN: Duration = Duration::from_millis(100);
+const RECONNECT_MAX: Duration = Duration::from_secs(5);
+
+macro_rules! try_return {
     ( $expr:expr ) => {
         match $expr {
             Some(val) => val,
-            None => continue
+            None => return false
         }
     };
 }
 
+/// Value of an informational target.
+enum InfoValue {
+    Integer(Atom, u32),
+    Text(String),
+}
+
 struct IncrState {
     selection: Atom,
+    target: Atom,
     requestor: Window,
     property: Atom,
     pos: usize
@@ -32,12 +49,30 @@ pub(crate) struct PipeDropFds {
 }
 
 pub(crate) fn create_pipe_drop_fd() -> Result<PipeDropFds, Error>{
+    create_pipe(libc::O_CLOEXEC)
+}
+
+/// Timeout in milliseconds for `poll`, -1 to wait forever.
+// `div_ceil` on integers needs Rust 1.73
+#[allow(clippy::manual_div_ceil)]
+pub(crate) fn poll_timeout(timeout: Option<Duration>) -> libc::c_int {
+    match timeout {
+        // Round up, so the deadline is not missed by less than a millisecond
+        Some(timeout) => {
+            let millis = (timeout.as_micros() + 999) / 1000;
+            cmp::min(millis, libc::c_int::MAX as u128) as libc::c_int
+        },
+        None => -1
+    }
+}
+
+pub(crate) fn create_pipe(flags: libc::c_int) -> Result<PipeDropFds, Error>{
     let pipe_drop_fds = unsafe {
         // Docs Linux: https://man7.org/linux/man-pages/man2/pipe.2.html
         // Posix: https://pubs.opengroup.org/onlinepubs/9699919799/
         // Safety: See above docs, api expects a 2-long array of file descriptors, and flags
         let mut pipes: [libc::c_int; 2] = [0, 0];
-        let pipe_create_res = libc::pipe2(pipes.as_mut_ptr(), libc::O_CLOEXEC);
+        let pipe_create_res = libc::pipe2(pipes.as_mut_ptr(), flags);
         if pipe_create_res < 0 {
             // Don't want to have to read from errno_location, just skip propagating errno.
             return Err(Error::EventFdCreate);
@@ -53,167 +88,951 @@ pub(crate) fn create_pipe_drop_fd() -> Result<PipeDropFds, Error>{
     Ok(pipe_drop_fds)
 }
 
-pub(crate) fn run(context: Arc<Context>, setmap: SetMap, max_length: usize, receiver: Receiver<Atom>, read_pipe: OwnedFd) {
-    let mut incr_map = HashMap::<Atom, Atom>::new();
-    let mut state_map = HashMap::<Atom, IncrState>::new();
+/// Selection owner state, answering the requests of other clients.
+pub(crate) struct Owner<C: Connection> {
+    context: Arc<Context<C>>,
+    setmap: SetMap,
+    incr_limit: Option<usize>,
+    incr_threshold: usize,
+    incr_chunk_size: usize,
+    incr_map: HashMap<Atom, Atom>,
+    state_map: HashMap<Atom, IncrState>,
+    closing: bool,
+    audit: Option<Sender<Access>>,
+    policy: Option<SharedPolicy>,
+    effects: Option<SharedEffects>,
+    // Boxed, the owner is stored inline in `Backend::Shared`
+    process: Option<Box<ProcessInfo>>,
+}
 
-    let stream_fd = context.connection.stream().as_fd();
-    let borrowed_fd = read_pipe.as_fd();
-    // Poll stream for new Read-ready events, check if the other side of the pipe has been dropped
-    let mut pollfds: [libc::pollfd; 2] = [libc::pollfd {
-        fd: stream_fd.as_raw_fd(),
-        events: libc::POLLIN,
-        revents: 0,
-    }, libc::pollfd {
-        fd: borrowed_fd.as_raw_fd(),
-        // If the other end is dropped, this pipe will get a HUP on poll
-        events: libc::POLLHUP,
-        revents: 0,
-    }];
-    let len = pollfds.len();
-    loop {
-        unsafe {
-            // Docs Linux: https://man7.org/linux/man-pages/man2/poll.2.html
-            // Posix: https://pubs.opengroup.org/onlinepubs/9699919799/
-            // Safety: Passing in a mutable pointer that lives for the duration of the call, the length is
-            // set to the length of that pointer.
-            // Any negative value (-1 for example) means infinite timeout.
-            let poll_res = libc::poll(&mut pollfds as *mut libc::pollfd, len as libc::nfds_t, -1);
-            if poll_res < 0 {
-                // Error polling, can't continue
-                return;
+impl<C: Connection> Owner<C> {
+    pub(crate) fn new(context: Arc<Context<C>>, setmap: SetMap, config: &Config) -> Owner<C> {
+        let mut owner = Owner {
+            context, setmap,
+            incr_limit: config.incr_threshold,
+            incr_threshold: 0,
+            incr_chunk_size: config.incr_chunk_size,
+            incr_map: HashMap::new(),
+            state_map: HashMap::new(),
+            closing: false,
+            audit: config.audit.clone(),
+            policy: config.policy.clone(),
+            effects: config.effects.clone(),
+            process: if config.info_targets { Some(Box::new(ProcessInfo::current())) } else { None },
+        };
+        owner.reset_limits();
+        owner
+    }
+
+    fn reset_limits(&mut self) {
+        let max_length = self.context.connection.maximum_request_bytes() - 24;
+        self.incr_threshold = cmp::min(self.incr_limit.unwrap_or(max_length), max_length);
+        self.incr_chunk_size = cmp::max(cmp::min(self.incr_chunk_size, max_length), 1);
+    }
+
+    /// Take ownership of a selection with a new value, at the server time of the entry.
+    pub(crate) fn store(&mut self, selection: Atom, entry: Entry) -> Result<(), Error> {
+        let time = entry.time;
+        self.cancel(selection);
+        self.setmap
+            .write()
+            .map_err(|_| Error::Lock)?
+            .insert(selection, entry);
+
+        let result = self.own(selection, time);
+        if result.is_err() {
+            if let Ok(mut setmap) = self.setmap.write() {
+                setmap.remove(&selection);
             }
         }
-        if pollfds[1].revents & libc::POLLHUP != 0 {
-            // kill-signal on pollfd
-            return;
+        result
+    }
+
+    fn own(&self, selection: Atom, time: Timestamp) -> Result<(), Error> {
+        self.context.connection.set_selection_owner(
+            self.context.window,
+            selection,
+            time
+        )?.check()?;
+
+        let owner = self.context.connection.get_selection_owner(selection)?.reply()?.owner;
+        if owner != self.context.window {
+            return Err(Error::Owner);
         }
-        loop {
-            let evt = if let Ok(evt) = context.connection.poll_for_event() {
-                evt
-            } else {
-                // Connection died, exit
-                return;
+
+        Ok(())
+    }
+
+    /// Give up a selection at server time `time`, whether it is owned by us or by another client.
+    pub(crate) fn clear(&mut self, selection: Atom, time: Timestamp) -> Result<(), Error> {
+        self.forget(selection)?;
+
+        self.context.connection.set_selection_owner(
+            x11rb::NONE,
+            selection,
+            time
+        )?.check()?;
+        self.context.connection.flush()?;
+        Ok(())
+    }
+
+    /// Drop the value of a selection and wipe it, without giving up the selection.
+    fn forget(&mut self, selection: Atom) -> Result<(), Error> {
+        self.cancel(selection);
+        if let Some(mut entry) = self.setmap.write().map_err(|_| Error::Lock)?.remove(&selection) {
+            wipe_entry(&mut entry);
+        }
+        Ok(())
+    }
+
+    /// Give up the selections whose time to live is over.
+    ///
+    /// They are cleared at the time they were stored, so this does nothing if another client took them since.
+    pub(crate) fn expire(&mut self, now: Instant) -> Result<(), Error> {
+        let expired = self.setmap
+            .read()
+            .map_err(|_| Error::Lock)?
+            .iter()
+            .filter(|(_, entry)| entry.expires.map(|expires| expires <= now).unwrap_or(false))
+            .map(|(&selection, entry)| (selection, entry.time))
+            .collect::<Vec<_>>();
+        for (selection, time) in expired {
+            self.clear(selection, time)?;
+        }
+        Ok(())
+    }
+
+    /// Convert a value stored with a source to `target`, keeping the result in its entry.
+    ///
+    /// This blocks until the source answers, it is expected to time out.
+    fn fetch(&self, selection: Atom, target: Atom) {
+        let (source, time) = {
+            let setmap = match self.setmap.read() {
+                Ok(setmap) => setmap,
+                Err(_) => return
             };
-            let event = if let Some(evt) = evt {
-                evt
+            let entry = match setmap.get(&selection) {
+                Some(entry) => entry,
+                None => return
+            };
+            let source = match entry.source {
+                Some(ref source) => Arc::clone(source),
+                None => return
+            };
+            let known = if target == self.context.atoms.targets {
+                entry.source_targets.is_some()
             } else {
-                // No event on POLLIN happens, fd being readable doesn't mean there's a complete event ready to read.
-                // Poll again.
-                break;
+                entry.values.iter().any(|&(value_target, _)| value_target == target)
             };
-            loop {
-                match receiver.try_recv() {
-                    Ok(selection) => if let Some(property) = incr_map.remove(&selection) {
-                        state_map.remove(&property);
-                    },
-                    Err(TryRecvError::Empty) => break,
-                    Err(TryRecvError::Disconnected) => if state_map.is_empty() {
-                        return
-                    }
+            if known {
+                return
+            }
+            (source, entry.time)
+        };
+
+        if target == self.context.atoms.targets {
+            if let Ok(targets) = source.targets() {
+                self.update(selection, time, |entry| entry.source_targets = Some(targets));
+            }
+        } else if let Ok(data) = source.convert(target) {
+            if data.check_format().is_ok() {
+                self.update(selection, time, |entry| entry.values.push((target, data)));
+            }
+        }
+    }
+
+    /// Change the entry of a selection, unless it was stored again since `time`.
+    fn update<F: FnOnce(&mut Entry)>(&self, selection: Atom, time: Timestamp, update: F) {
+        if let Ok(mut setmap) = self.setmap.write() {
+            if let Some(entry) = setmap.get_mut(&selection).filter(|entry| entry.time == time) {
+                update(entry);
+            }
+        }
+    }
+
+    /// Count a complete transfer of a selection, giving it up after its last allowed read.
+    fn count_read(&mut self, selection: Atom) {
+        let time = {
+            let mut setmap = match self.setmap.write() {
+                Ok(setmap) => setmap,
+                Err(_) => return
+            };
+            let entry = match setmap.get_mut(&selection) {
+                Some(entry) => entry,
+                None => return
+            };
+            match entry.reads_left {
+                // Never 0, `store_limited` refuses it
+                Some(ref mut reads_left) if *reads_left > 1 => {
+                    *reads_left -= 1;
+                    return
+                },
+                Some(_) => entry.time,
+                None => return
+            }
+        };
+        let _ = self.clear(selection, time);
+    }
+
+    /// When the next selection expires.
+    pub(crate) fn next_expiry(&self) -> Option<Instant> {
+        self.setmap.read().ok()?
+            .values()
+            .filter_map(|entry| entry.expires)
+            .min()
+    }
+
+    /// INCR transfers in progress.
+    pub(crate) fn transfers(&self) -> Vec<Transfer> {
+        let setmap = match self.setmap.read() {
+            Ok(setmap) => setmap,
+            Err(_) => return Vec::new()
+        };
+
+        self.state_map.values()
+            .filter_map(|state| setmap.get(&state.selection).map(|entry| Transfer {
+                selection: state.selection,
+                target: state.target,
+                requestor: state.requestor,
+                property: state.property,
+                sent: state.pos,
+                size: entry.get(state.target).map(|(_, data)| data.bytes.len()).unwrap_or(0),
+            }))
+            .collect()
+    }
+
+    /// Switch to a new connection, taking the held selections over to it.
+    ///
+    /// The server may have restarted, so the atoms are looked up by name and the selections
+    /// are owned again at its current time. The ones that can't be taken over are dropped.
+    fn reconnect(&mut self, context: Arc<Context<C>>) -> Result<(), Error> {
+        let old = mem::replace(&mut self.context, context);
+        self.incr_map.clear();
+        self.state_map.clear();
+        self.reset_limits();
+
+        let time = self.server_time()?;
+        let entries = mem::take(&mut *self.setmap.write().map_err(|_| Error::Lock)?);
+        for (selection, mut entry) in entries {
+            let moved = translate_entry(&old, &self.context, selection, &mut entry)
+                .and_then(|selection| self.own(selection, time).map(|()| selection));
+            match moved {
+                Ok(selection) => {
+                    entry.time = time;
+                    self.setmap.write().map_err(|_| Error::Lock)?.insert(selection, entry);
+                },
+                Err(_) => wipe_entry(&mut entry)
+            }
+        }
+        Ok(())
+    }
+
+    /// Current server time, from the `PropertyNotify` of a zero-length append to the setter window.
+    ///
+    /// The other events read meanwhile are handled as usual.
+    fn server_time(&mut self) -> Result<Timestamp, Error> {
+        let context = Arc::clone(&self.context);
+        context.connection.change_property(
+            PropMode::APPEND,
+            context.window,
+            context.atoms.property,
+            AtomEnum::INTEGER,
+            32,
+            0,
+            &[]
+        )?.check()?;
+
+        loop {
+            match context.connection.wait_for_event()? {
+                Event::PropertyNotify(ref event)
+                    if event.window == context.window && event.atom == context.atoms.property => return Ok(event.time),
+                event => {
+                    self.handle_event(&event);
                 }
             }
+        }
+    }
+
+    /// Refuse the new requests, the INCR transfers in progress go on.
+    pub(crate) fn close(&mut self) {
+        self.closing = true;
+    }
+
+    /// Whether no INCR transfer is in progress.
+    pub(crate) fn is_idle(&self) -> bool {
+        self.state_map.is_empty()
+    }
 
-            match event {
-                Event::SelectionRequest(event) => {
-                    let read_map = try_continue!(setmap.read().ok());
-                    let &(target, ref value) = try_continue!(read_map.get(&event.selection));
+    /// Abort all the INCR transfers.
+    ///
+    /// The final empty chunk is not sent, so the requestors don't take a truncated value as complete.
+    pub(crate) fn abort(&mut self) {
+        self.incr_map.clear();
+        self.state_map.clear();
+    }
+
+    /// Abort the INCR transfers of a selection.
+    pub(crate) fn cancel(&mut self, selection: Atom) {
+        self.incr_map.remove(&selection);
+        self.state_map.retain(|_, state| state.selection != selection);
+    }
+
+    /// Read the side effect asked by a request, from the property of the requestor.
+    fn side_effect(&self, event: &SelectionRequestEvent) -> Option<SideEffect> {
+        let atoms = &self.context.atoms;
+        if event.target == atoms.delete {
+            return Some(SideEffect::Delete)
+        }
+        if event.property == x11rb::NONE {
+            return None
+        }
+
+        let reply = self.context.connection.get_property(
+            false,
+            event.requestor,
+            event.property,
+            AtomEnum::ANY,
+            0,
+            u32::MAX
+        ).ok()?.reply().ok()?;
+
+        if event.target == atoms.insert_selection {
+            // An ATOM_PAIR of the selection and the target to insert
+            let mut pair = reply.value32()?;
+            Some(SideEffect::InsertSelection { selection: pair.next()?, target: pair.next()? })
+        } else {
+            Some(SideEffect::InsertProperty { type_: reply.type_, value: reply.value })
+        }
+    }
+
+    /// Name of the informational target `target`, when they are answered.
+    fn info_target(&self, target: Atom) -> Option<&'static str> {
+        self.process.as_ref()?;
+        let atoms = self.context.get_atoms(INFO_TARGETS).ok()?;
+        atoms.iter()
+            .position(|&atom| atom == target)
+            .map(|i| INFO_TARGETS[i])
+    }
+
+    /// Value of an informational target for a stored selection.
+    fn info_value(&self, name: &str, entry: &Entry) -> Option<InfoValue> {
+        let process = self.process.as_ref()?;
+        let integer = Atom::from(AtomEnum::INTEGER);
+        match name {
+            // The length of a secret is kept for itself
+            "LENGTH" if !entry.secret => entry.values.first()
+                .map(|(_, data)| InfoValue::Integer(integer, data.bytes.len() as u32)),
+            "CLIENT_WINDOW" => Some(InfoValue::Integer(Atom::from(AtomEnum::WINDOW), self.context.window)),
+            "PROCESS" => Some(InfoValue::Integer(integer, process.pid)),
+            "HOSTNAME" => process.hostname.clone().map(InfoValue::Text),
+            "NAME" => process.name.clone().map(InfoValue::Text),
+            "USER" => process.user.clone().map(InfoValue::Text),
+            "OWNER_OS" => process.os.clone().map(InfoValue::Text),
+            _ => None
+        }
+    }
+
+    /// Handle an event, returns `true` if it was meant for the owner.
+    pub(crate) fn handle_event(&mut self, event: &Event) -> bool {
+        let context = &self.context;
+
+        match *event {
+            Event::SelectionRequest(ref event) => {
+                if event.owner != context.window { return false };
+
+                // Looked up before answering, the requestor may be gone afterwards
+                let client = match (&self.audit, &self.policy) {
+                    (None, None) => None,
+                    _ => OwnerInfo::query(context, event.requestor).ok()
+                };
+
+                let atoms = &context.atoms;
+                let side_effect = [atoms.delete, atoms.insert_selection, atoms.insert_property]
+                    .contains(&event.target);
+
+                let info = self.info_target(event.target);
+                let timestamp = context.get_atom("TIMESTAMP").ok();
+
+                let mut served = false;
+                let mut refused = self.closing;
+                let (mut bytes, mut incr) = (0, false);
+                if !refused {
+                    let read_map = try_return!(self.setmap.read().ok());
+                    let entry = try_return!(read_map.get(&event.selection));
+
+                    let allowed = self.policy.as_ref().map(|policy| policy.0.allow(&AccessRequest {
+                        requestor: event.requestor,
+                        pid: client.as_ref().and_then(|client| client.pid),
+                        instance: client.as_ref().and_then(|client| client.instance.clone()),
+                        class: client.as_ref().and_then(|client| client.class.clone()),
+                        selection: event.selection,
+                        target: event.target,
+                        secret: entry.secret,
+                    }));
+                    refused = allowed == Some(false);
+                }
+
+                // Denied requestors don't get to trigger a conversion
+                if !refused && !side_effect && info.is_none() && timestamp != Some(event.target) {
+                    self.fetch(event.selection, event.target);
+                }
+
+                if !refused {
+                    let read_map = try_return!(self.setmap.read().ok());
+                    let entry = try_return!(read_map.get(&event.selection));
+                    let hint = context.atoms.password_manager_hint;
 
                     if event.target == context.atoms.targets {
+                        let mut targets = vec![context.atoms.targets];
+                        targets.extend(timestamp);
+                        let known = entry.values.iter().map(|&(target, _)| target)
+                            .chain(entry.source_targets.iter().flatten().cloned());
+                        for target in known {
+                            if !targets.contains(&target) {
+                                targets.push(target);
+                            }
+                        }
+                        if entry.secret {
+                            targets.push(hint);
+                        }
+                        if self.effects.is_some() {
+                            targets.extend_from_slice(&[atoms.delete, atoms.insert_selection, atoms.insert_property]);
+                        }
+                        if self.process.is_some() {
+                            targets.extend(context.get_atoms(INFO_TARGETS).into_iter().flatten());
+                        }
                         let _ = x11rb::wrapper::ConnectionExt::change_property32(
-                            &context.connection,
+                            &*context.connection,
                             PropMode::REPLACE,
                             event.requestor,
                             event.property,
                             Atom::from(AtomEnum::ATOM),
-                            &[context.atoms.targets, target]
+                            &targets
                         );
-                    } else if value.len() < max_length - 24 {
-                        let _ = x11rb::wrapper::ConnectionExt::change_property8(
-                            &context.connection,
+                        bytes = targets.len() * 4;
+                    } else if timestamp == Some(event.target) {
+                        // The server time the selection was taken at
+                        let _ = x11rb::wrapper::ConnectionExt::change_property32(
+                            &*context.connection,
                             PropMode::REPLACE,
                             event.requestor,
                             event.property,
-                            target,
-                            value
-                        );
-                    } else {
-                        let _ = context.connection.change_window_attributes(
-                            event.requestor,
-                            &ChangeWindowAttributesAux::new()
-                                .event_mask(EventMask::PROPERTY_CHANGE)
+                            Atom::from(AtomEnum::INTEGER),
+                            &[entry.time]
                         );
-                        let _ = x11rb::wrapper::ConnectionExt::change_property32(
-                            &context.connection,
+                        bytes = 4;
+                    } else if side_effect {
+                        let performed = match (&self.effects, self.side_effect(event)) {
+                            (Some(effects), Some(effect)) => effects.0.perform(&SideEffectRequest {
+                                requestor: event.requestor,
+                                selection: event.selection,
+                                effect
+                            }),
+                            _ => false
+                        };
+                        if performed {
+                            // ICCCM asks for a zero-length property of type NULL
+                            let _ = x11rb::wrapper::ConnectionExt::change_property8(
+                                &*context.connection,
+                                PropMode::REPLACE,
+                                event.requestor,
+                                event.property,
+                                atoms.null,
+                                &[]
+                            );
+                        } else {
+                            refused = true;
+                        }
+                    } else if let Some(name) = info {
+                        match self.info_value(name, entry) {
+                            Some(InfoValue::Integer(type_, value)) => {
+                                let _ = x11rb::wrapper::ConnectionExt::change_property32(
+                                    &*context.connection,
+                                    PropMode::REPLACE,
+                                    event.requestor,
+                                    event.property,
+                                    type_,
+                                    &[value]
+                                );
+                                bytes = 4;
+                            },
+                            Some(InfoValue::Text(text)) => {
+                                let _ = x11rb::wrapper::ConnectionExt::change_property8(
+                                    &*context.connection,
+                                    PropMode::REPLACE,
+                                    event.requestor,
+                                    event.property,
+                                    atoms.string,
+                                    text.as_bytes()
+                                );
+                                bytes = text.len();
+                            },
+                            None => refused = true
+                        }
+                    } else if event.target == hint && entry.secret {
+                        let _ = x11rb::wrapper::ConnectionExt::change_property8(
+                            &*context.connection,
                             PropMode::REPLACE,
                             event.requestor,
                             event.property,
-                            context.atoms.incr,
-                            &[0u32; 0],
+                            hint,
+                            b"secret"
                         );
-                        incr_map.insert(event.selection, event.property);
-                        state_map.insert(
-                            event.property,
-                            IncrState {
-                                selection: event.selection,
-                                requestor: event.requestor,
-                                property: event.property,
-                                pos: 0
-                            }
-                        );
-                    }
-                    let _ = context.connection.send_event(
-                        false,
-                        event.requestor,
-                        EventMask::default(),
-                        SelectionNotifyEvent {
-                            response_type: SELECTION_NOTIFY_EVENT,
-                            sequence: 0,
-                            time: event.time,
-                            requestor: event.requestor,
-                            selection: event.selection,
-                            target: event.target,
-                            property: event.property
+                        bytes = 6;
+                    } else if let Some((target, data)) = entry.get(event.target) {
+                        if data.bytes.len() < self.incr_threshold {
+                            write_value(context, event.requestor, event.property, data, &data.bytes);
+                            served = true;
+                        } else {
+                            // Destroying the requestor window ends the transfer
+                            let _ = context.connection.change_window_attributes(
+                                event.requestor,
+                                &ChangeWindowAttributesAux::new()
+                                    .event_mask(EventMask::PROPERTY_CHANGE | EventMask::STRUCTURE_NOTIFY)
+                            );
+                            let _ = x11rb::wrapper::ConnectionExt::change_property32(
+                                &*context.connection,
+                                PropMode::REPLACE,
+                                event.requestor,
+                                event.property,
+                                context.atoms.incr,
+                                &[0u32; 0],
+                            );
+                            self.incr_map.insert(event.selection, event.property);
+                            self.state_map.insert(
+                                event.property,
+                                IncrState {
+                                    selection: event.selection,
+                                    target,
+                                    requestor: event.requestor,
+                                    property: event.property,
+                                    pos: 0
+                                }
+                            );
+                            incr = true;
                         }
-                    );
-                    let _ = context.connection.flush();
-                },
-                Event::PropertyNotify(event) => {
-                    if event.state != Property::DELETE { continue };
+                        bytes = data.bytes.len();
+                    } else {
+                        // The source could not convert it
+                        refused = true;
+                    }
+                }
+                let property = if refused { x11rb::NONE } else { event.property };
+                let _ = context.connection.send_event(
+                    false,
+                    event.requestor,
+                    EventMask::default(),
+                    SelectionNotifyEvent {
+                        response_type: SELECTION_NOTIFY_EVENT,
+                        sequence: 0,
+                        time: event.time,
+                        requestor: event.requestor,
+                        selection: event.selection,
+                        target: event.target,
+                        property
+                    }
+                );
+                let _ = context.connection.flush();
+                if let Some(ref audit) = self.audit {
+                    let _ = audit.send(Access {
+                        requestor: event.requestor,
+                        pid: client.as_ref().and_then(|client| client.pid),
+                        class: client.and_then(|client| client.class),
+                        selection: event.selection,
+                        target: event.target,
+                        property, bytes, incr,
+                    });
+                }
+                if served {
+                    self.count_read(event.selection);
+                }
+                true
+            },
+            Event::PropertyNotify(ref event) => {
+                if event.state != Property::DELETE { return false };
 
-                    let is_end = {
-                        let state = try_continue!(state_map.get_mut(&event.atom));
-                        let read_setmap = try_continue!(setmap.read().ok());
-                        let &(target, ref value) = try_continue!(read_setmap.get(&state.selection));
+                let is_end = {
+                    let state = try_return!(self.state_map.get_mut(&event.atom));
+                    if state.requestor != event.window { return false };
+                    let read_setmap = try_return!(self.setmap.read().ok());
+                    let entry = try_return!(read_setmap.get(&state.selection));
+                    let (_, data) = try_return!(entry.get(state.target));
 
-                        let len = cmp::min(INCR_CHUNK_SIZE, value.len() - state.pos);
-                        let _ = x11rb::wrapper::ConnectionExt::change_property8(
-                            &context.connection,
-                            PropMode::REPLACE,
-                            state.requestor,
-                            state.property,
-                            target,
-                            &value[state.pos..][..len]
-                        );
-                        state.pos += len;
-                        len == 0
-                    };
+                    // Chunks end on element boundaries
+                    let width = data.width();
+                    let chunk_size = cmp::max(self.incr_chunk_size / width * width, width);
+                    let len = cmp::min(chunk_size, data.bytes.len() - state.pos);
+                    write_value(context, state.requestor, state.property, data, &data.bytes[state.pos..][..len]);
+                    state.pos += len;
+                    len == 0
+                };
 
-                    if is_end {
-                        state_map.remove(&event.atom);
+                let _ = context.connection.flush();
+                if is_end {
+                    if let Some(state) = self.state_map.remove(&event.atom) {
+                        self.count_read(state.selection);
                     }
-                    let _ = context.connection.flush();
-                },
-                Event::SelectionClear(event) => {
-                    if let Some(property) = incr_map.remove(&event.selection) {
-                        state_map.remove(&property);
+                }
+                true
+            },
+            Event::DestroyNotify(ref event) => {
+                // The requestor left, its transfers would never be acknowledged
+                let properties = self.state_map.values()
+                    .filter(|state| state.requestor == event.window)
+                    .map(|state| state.property)
+                    .collect::<Vec<_>>();
+                if properties.is_empty() { return false };
+
+                self.state_map.retain(|_, state| state.requestor != event.window);
+                self.incr_map.retain(|_, property| !properties.contains(property));
+                true
+            },
+            Event::SelectionClear(ref event) => {
+                if event.owner != context.window { return false };
+
+                self.cancel(event.selection);
+                if let Ok(mut write_setmap) = self.setmap.write() {
+                    write_setmap.remove(&event.selection);
+                }
+                true
+            }
+            _ => false
+        }
+    }
+}
+
+/// Write part of a value to a property, with the type and format of the value.
+fn write_value<C>(context: &Context<C>, window: Window, property: Atom, data: &SelectionData, bytes: &[u8])
+    where C: Connection
+{
+    let _ = context.connection.change_property(
+        PropMode::REPLACE,
+        window,
+        property,
+        data.type_,
+        data.format,
+        (bytes.len() / data.width()) as u32,
+        bytes
+    );
+}
+
+/// Overwrite a value before it is freed.
+/// Wipe the values of a dropped entry.
+fn wipe_entry(entry: &mut Entry) {
+    for (_, data) in entry.values.iter_mut() {
+        wipe(&mut data.bytes);
+    }
+}
+
+/// Atoms of the old connection, looked up by name on the new one.
+///
+/// Predefined atoms are the same on every server, the names of the others must be in the old cache.
+fn translate<C: Connection>(old: &Context<C>, new: &Context<C>, atoms: &[Atom]) -> Result<Vec<Atom>, Error> {
+    let predefined = |atom: Atom| atom <= Atom::from(AtomEnum::WM_TRANSIENT_FOR);
+    let names = old.get_atom_names(&atoms.iter().cloned().filter(|&atom| !predefined(atom)).collect::<Vec<_>>())?;
+    let mut translated = new.get_atoms(&names)?.into_iter();
+    Ok(atoms.iter().map(|&atom| if predefined(atom) { atom } else { translated.next().unwrap_or(atom) }).collect())
+}
+
+/// Translate the selection, targets and types of an entry to a new connection, returns the new selection.
+fn translate_entry<C: Connection>(old: &Context<C>, new: &Context<C>, selection: Atom, entry: &mut Entry)
+    -> Result<Atom, Error>
+{
+    for &mut (ref mut target, ref mut data) in entry.values.iter_mut() {
+        if data.type_ == Atom::from(AtomEnum::ATOM) {
+            if let Some(atoms) = data.as_atoms() {
+                *data = SelectionData::from_u32s(data.type_, &translate(old, new, &atoms)?);
+            }
+        }
+        let atoms = translate(old, new, &[*target, data.type_])?;
+        *target = atoms[0];
+        data.type_ = atoms[1];
+    }
+    if let Some(ref mut targets) = entry.source_targets {
+        *targets = translate(old, new, targets)?;
+    }
+    Ok(translate(old, new, &[selection])?[0])
+}
+
+fn wipe(value: &mut [u8]) {
+    for byte in value.iter_mut() {
+        unsafe {
+            // Safety: Writing through a valid mutable reference, volatile so the writes are not optimized out.
+            ptr::write_volatile(byte, 0);
+        }
+    }
+}
+
+/// Requests from the clipboard to the background thread.
+pub(crate) enum Command {
+    Store {
+        selection: Atom,
+        entry: Entry,
+        reply: Sender<Result<(), Error>>,
+    },
+    Clear {
+        selection: Atom,
+        time: Timestamp,
+        reply: Sender<Result<(), Error>>,
+    },
+    Transfers {
+        reply: Sender<Result<Vec<Transfer>, Error>>,
+    },
+    /// Stop once the INCR transfers are done, or at `deadline`.
+    Shutdown {
+        deadline: Instant,
+        reply: Sender<Result<(), Error>>,
+    },
+}
+
+pub(crate) type SharedHealth = Arc<Mutex<Health>>;
+
+/// New getter and setter contexts, with the fds of their connections.
+pub(crate) struct Link<C: Connection> {
+    pub(crate) getter: Context<C>,
+    pub(crate) getter_fd: RawFd,
+    pub(crate) setter: Context<C>,
+    pub(crate) setter_fd: RawFd,
+}
+
+/// Opens new contexts, on new connections.
+pub(crate) type Connect<C> = Box<dyn Fn() -> Result<Link<C>, Error> + Send>;
+
+/// Everything the background thread needs.
+pub(crate) struct Worker<C: Connection> {
+    pub(crate) owner: Owner<C>,
+    pub(crate) stream_fd: RawFd,
+    /// Contexts of the clipboard, published again after reconnecting.
+    pub(crate) session: SharedSession<C>,
+    pub(crate) receiver: Receiver<Command>,
+    pub(crate) read_pipe: OwnedFd,
+    pub(crate) health: SharedHealth,
+    pub(crate) reconnect: Option<Connect<C>>,
+    pub(crate) shutdown: Option<(Instant, Sender<Result<(), Error>>)>,
+}
+
+enum Exit {
+    Stopped,
+    Disconnected,
+}
+
+/// Wake up the background thread to handle new commands.
+pub(crate) fn wake(write_pipe: &OwnedFd) {
+    unsafe {
+        // Safety: Writing one byte from a valid buffer, a failure means the thread is gone.
+        let byte = 1u8;
+        libc::write(write_pipe.as_raw_fd(), &byte as *const u8 as *const libc::c_void, 1);
+    }
+}
+
+fn set_health(health: &SharedHealth, value: Health) {
+    if let Ok(mut health) = health.lock() {
+        *health = value;
+    }
+}
+
+pub(crate) fn run<C: Connection>(mut worker: Worker<C>) {
+    loop {
+        match serve(&mut worker) {
+            Exit::Stopped => break,
+            Exit::Disconnected if worker.shutdown.is_some() => break,
+            Exit::Disconnected => {
+                let connect = match worker.reconnect.take() {
+                    Some(connect) => connect,
+                    None => {
+                        set_health(&worker.health, Health::Disconnected);
+                        return
                     }
-                    if let Ok(mut write_setmap) = setmap.write() {
-                        write_setmap.remove(&event.selection);
+                };
+                set_health(&worker.health, Health::Reconnecting);
+
+                let mut delay = RECONNECT_MIN;
+                loop {
+                    // Wait before retrying, unless the clipboard was dropped or shut down meanwhile
+                    if !wait_pipe(&worker.read_pipe, delay) || !refuse_commands(&worker.receiver) {
+                        set_health(&worker.health, Health::Stopped);
+                        return
                     }
+                    if let Ok(link) = connect() {
+                        if switch(&mut worker, link).is_ok() {
+                            break
+                        }
+                    }
+                    delay = cmp::min(delay * 2, RECONNECT_MAX);
                 }
-                _ => ()
+
+                worker.reconnect = Some(connect);
+                set_health(&worker.health, Health::Running);
             }
         }
     }
+    set_health(&worker.health, Health::Stopped);
+}
+
+/// Take the selections over to new contexts, and hand these to the clipboard.
+fn switch<C: Connection>(worker: &mut Worker<C>, link: Link<C>) -> Result<(), Error> {
+    let dispatcher = Dispatcher::new(link.getter.window, Some(Reader::new(link.getter_fd)?));
+    let setter = Arc::new(link.setter);
+    worker.owner.reconnect(Arc::clone(&setter))?;
+    worker.stream_fd = link.setter_fd;
+
+    let session = Session { getter: Arc::new(link.getter), setter, dispatcher };
+    *worker.session.write().map_err(|_| Error::Lock)? = Arc::new(session);
+    Ok(())
+}
+
+/// Sleep up to `timeout` or until a command is sent, returns `false` if the other end of the pipe was dropped.
+fn wait_pipe(read_pipe: &OwnedFd, timeout: Duration) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd: read_pipe.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe {
+        // Safety: Passing in a pointer to a single pollfd that lives for the duration of the call.
+        libc::poll(&mut pollfd, 1, timeout.as_millis() as libc::c_int);
+    }
+    if pollfd.revents & libc::POLLIN != 0 {
+        let mut buf = [0u8; 64];
+        unsafe {
+            // Safety: Reading at most the length of the buffer into it,
+            // poll reported the pipe as readable so this does not block.
+            libc::read(read_pipe.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        }
+    }
+    pollfd.revents & libc::POLLHUP == 0
+}
+
+/// Fail the commands sent while there is no connection, returns `false` on shutdown.
+fn refuse_commands(receiver: &Receiver<Command>) -> bool {
+    while let Ok(command) = receiver.try_recv() {
+        match command {
+            Command::Store { reply, .. } | Command::Clear { reply, .. } => {
+                let _ = reply.send(Err(Error::Disconnected));
+            },
+            Command::Transfers { reply } => {
+                let _ = reply.send(Err(Error::Disconnected));
+            },
+            Command::Shutdown { reply, .. } => {
+                let _ = reply.send(Err(Error::Disconnected));
+                return false
+            }
+        }
+    }
+    true
+}
+
+fn serve<C: Connection>(worker: &mut Worker<C>) -> Exit {
+    let context = Arc::clone(&worker.owner.context);
+    let borrowed_fd = worker.read_pipe.as_fd();
+    // Poll stream for new Read-ready events, check if the other side of the pipe has been dropped
+    let mut pollfds: [libc::pollfd; 2] = [libc::pollfd {
+        fd: worker.stream_fd,
+        events: libc::POLLIN,
+        revents: 0,
+    }, libc::pollfd {
+        fd: borrowed_fd.as_raw_fd(),
+        // If the other end is dropped, this pipe will get a HUP on poll,
+        // it is readable when new commands were sent.
+        events: libc::POLLIN | libc::POLLHUP,
+        revents: 0,
+    }];
+    let len = pollfds.len();
+    loop {
+        // Replies read while handling commands may have queued events
+        loop {
+            let evt = if let Ok(evt) = context.connection.poll_for_event() {
+                evt
+            } else {
+                // Connection died
+                return Exit::Disconnected;
+            };
+            let event = if let Some(evt) = evt {
+                evt
+            } else {
+                // No event on POLLIN happens, fd being readable doesn't mean there's a complete event ready to read.
+                // Poll again.
+                break;
+            };
+
+            worker.owner.handle_event(&event);
+        }
+
+        // A lost connection shows up when polling for events
+        let now = Instant::now();
+        let _ = worker.owner.expire(now);
+        let mut deadline = worker.owner.next_expiry();
+
+        // When shutting down, wait for the INCR transfers up to the deadline
+        if let Some((shutdown, _)) = worker.shutdown {
+            if worker.owner.is_idle() || now >= shutdown {
+                let result = if worker.owner.is_idle() {
+                    Ok(())
+                } else {
+                    worker.owner.abort();
+                    Err(Error::Timeout)
+                };
+                if let Some((_, reply)) = worker.shutdown.take() {
+                    let _ = reply.send(result);
+                }
+                return Exit::Stopped;
+            }
+            deadline = Some(deadline.map_or(shutdown, |deadline| cmp::min(deadline, shutdown)));
+        }
+        let timeout = poll_timeout(deadline.map(|deadline| deadline.saturating_duration_since(now)));
+
+        unsafe {
+            // Docs Linux: https://man7.org/linux/man-pages/man2/poll.2.html
+            // Posix: https://pubs.opengroup.org/onlinepubs/9699919799/
+            // Safety: Passing in a mutable pointer that lives for the duration of the call, the length is
+            // set to the length of that pointer.
+            // Any negative value (-1 for example) means infinite timeout.
+            let poll_res = libc::poll(&mut pollfds as *mut libc::pollfd, len as libc::nfds_t, timeout);
+            if poll_res < 0 {
+                if std::io::Error::last_os_error().kind() == std::io::ErrorKind::Interrupted {
+                    continue;
+                }
+                // Error polling, can't continue
+                return Exit::Disconnected;
+            }
+        }
+        if pollfds[0].revents & (libc::POLLERR | libc::POLLHUP) != 0 {
+            return Exit::Disconnected;
+        }
+        if pollfds[1].revents & libc::POLLIN != 0 {
+            let mut buf = [0u8; 64];
+            unsafe {
+                // Safety: Reading at most the length of the buffer into it,
+                // poll reported the pipe as readable so this does not block.
+                libc::read(borrowed_fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+            }
+        }
+        loop {
+            match worker.receiver.try_recv() {
+                Ok(Command::Store { selection, entry, reply }) => {
+                    let _ = reply.send(worker.owner.store(selection, entry));
+                },
+                Ok(Command::Clear { selection, time, reply }) => {
+                    let _ = reply.send(worker.owner.clear(selection, time));
+                },
+                Ok(Command::Transfers { reply }) => {
+                    let _ = reply.send(Ok(worker.owner.transfers()));
+                },
+                Ok(Command::Shutdown { deadline, reply }) => {
+                    worker.owner.close();
+                    worker.shutdown = Some((deadline, reply));
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => if worker.owner.state_map.is_empty() {
+                    return Exit::Stopped
+                } else {
+                    break
+                }
+            }
+        }
+        if pollfds[1].revents & libc::POLLHUP != 0 {
+            // kill-signal on pollfd
+            return Exit::Stopped;
+        }
+    }
 }
@@ -0,0 +1,45 @@
+use ::Atom;
+
+
+/// A selection, by its well-known name or by atom.
+///
+/// Atoms and names convert into it, so either can be passed to `Clipboard` methods.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum Selection {
+    Primary,
+    Secondary,
+    Clipboard,
+    /// Any other selection, such as `XdndSelection`, interned when used.
+    Named(String),
+    /// An already interned selection.
+    Atom(Atom),
+}
+
+impl From<Atom> for Selection {
+    fn from(atom: Atom) -> Selection {
+        Selection::Atom(atom)
+    }
+}
+
+impl<'a> From<&'a str> for Selection {
+    fn from(name: &'a str) -> Selection {
+        Selection::Named(name.into())
+    }
+}
+
+impl From<String> for Selection {
+    fn from(name: String) -> Selection {
+        Selection::Named(name)
+    }
+}
+
+/// Direction of `Clipboard::sync`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncMode {
+    /// Copy PRIMARY to CLIPBOARD, so selecting text is enough to copy it.
+    PrimaryToClipboard,
+    /// Copy CLIPBOARD to PRIMARY.
+    ClipboardToPrimary,
+    /// Copy each one to the other.
+    Both,
+}
@@ -0,0 +1,46 @@
+use ::{ Atom, Timestamp, Window };
+
+
+/// State of the background thread.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Health {
+    /// Serving requests, also used when the connection is shared with the application.
+    Running,
+    /// Lost its connection and trying to open a new one.
+    Reconnecting,
+    /// Lost its connection and exited.
+    Disconnected,
+    /// Exited.
+    Stopped,
+}
+
+/// Selection currently owned by the clipboard.
+#[derive(Clone, Debug)]
+pub struct OwnedSelection {
+    pub selection: Atom,
+    /// First target of the value.
+    pub target: Atom,
+    /// Size of the value, for all its targets.
+    pub size: usize,
+    /// Server time at which the ownership was taken.
+    pub timestamp: Timestamp,
+}
+
+/// INCR transfer in progress.
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    pub selection: Atom,
+    pub target: Atom,
+    pub requestor: Window,
+    pub property: Atom,
+    pub sent: usize,
+    pub size: usize,
+}
+
+/// Clipboard Status, see `Clipboard::status`.
+#[derive(Clone, Debug)]
+pub struct Status {
+    pub health: Health,
+    pub selections: Vec<OwnedSelection>,
+    pub transfers: Vec<Transfer>,
+}
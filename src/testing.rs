@@ -0,0 +1,326 @@
+//! Misbehaving selection owners and requestors, to test clipboard code against hostile peers.
+//!
+//! ```no_run
+//! # use std::time::Duration;
+//! # use x11_clipboard::Clipboard;
+//! # use x11_clipboard::testing::{ Behavior, FakeOwner };
+//! let owner = FakeOwner::new(None, "TEST_SELECTION", vec![Behavior::Refuse]).unwrap();
+//! let clipboard = Clipboard::new().unwrap();
+//! let utf8_string = clipboard.getter.atoms.utf8_string;
+//! let property = clipboard.getter.atoms.property;
+//! assert!(clipboard.load(owner.selection(), utf8_string, property, Duration::from_secs(1)).is_err());
+//! ```
+
+use std::cmp;
+use std::sync::Arc;
+use std::sync::atomic::{ AtomicBool, Ordering };
+use std::thread::{ self, JoinHandle };
+use std::time::{ Duration, Instant };
+use x11rb::connection::Connection;
+use x11rb::protocol::Event;
+use x11rb::protocol::xproto::{
+    AtomEnum, ChangeWindowAttributesAux, ConnectionExt, CreateWindowAux, EventMask, Property, PropMode,
+    SelectionNotifyEvent, SelectionRequestEvent, WindowClass, SELECTION_NOTIFY_EVENT
+};
+use x11rb::rust_connection::RustConnection;
+use x11rb::{ COPY_DEPTH_FROM_PARENT, CURRENT_TIME };
+use ::{ Atom, Window };
+use error::Error;
+
+const CHUNK_SIZE: usize = 1024;
+
+/// How a `FakeOwner` answers a request.
+#[derive(Clone, Debug)]
+pub enum Behavior {
+    /// Send the value, with the requested target as type.
+    Serve(Vec<u8>),
+    /// Answer with a `None` property.
+    Refuse,
+    /// Send the value with another type.
+    WrongType(Atom, Vec<u8>),
+    /// Send the value with INCR, but stop after `chunks` chunks.
+    StallIncr { value: Vec<u8>, chunks: usize },
+    /// Send the value with INCR, announcing `size` bytes.
+    BogusIncrSize { value: Vec<u8>, size: u32 },
+    /// Never answer.
+    Ignore,
+    /// Destroy the owner window instead of answering.
+    Vanish,
+}
+
+/// A selection owner answering the requests as scripted.
+///
+/// Each request is answered with the next behavior of the script, the last one is repeated.
+pub struct FakeOwner {
+    connection: Arc<RustConnection>,
+    window: Window,
+    selection: Atom,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+/// INCR transfer in progress.
+struct Transfer {
+    requestor: Window,
+    property: Atom,
+    target: Atom,
+    value: Vec<u8>,
+    pos: usize,
+    chunks_left: Option<usize>,
+}
+
+/// A requestor driving conversions step by step, so it can stop at any point.
+pub struct FakeRequestor {
+    connection: RustConnection,
+    window: Window,
+}
+
+fn create_window(connection: &RustConnection, screen: usize) -> Result<Window, Error> {
+    let root = &connection.setup().roots[screen];
+    let window = connection.generate_id()?;
+    connection.create_window(
+        COPY_DEPTH_FROM_PARENT,
+        window,
+        root.root,
+        0, 0, 1, 1, 0,
+        WindowClass::INPUT_OUTPUT,
+        root.root_visual,
+        &CreateWindowAux::new().event_mask(EventMask::PROPERTY_CHANGE)
+    )?.check()?;
+    Ok(window)
+}
+
+fn intern(connection: &RustConnection, name: &str) -> Result<Atom, Error> {
+    Ok(connection.intern_atom(false, name.as_bytes())?.reply()?.atom)
+}
+
+impl FakeOwner {
+    /// Take ownership of the selection `name` on `display`, and answer its requests with `script`.
+    pub fn new(display: Option<&str>, name: &str, script: Vec<Behavior>) -> Result<FakeOwner, Error> {
+        let (connection, screen) = RustConnection::connect(display)?;
+        let window = create_window(&connection, screen)?;
+        let selection = intern(&connection, name)?;
+        let incr = intern(&connection, "INCR")?;
+
+        connection.set_selection_owner(window, selection, CURRENT_TIME)?.check()?;
+        if connection.get_selection_owner(selection)?.reply()?.owner != window {
+            return Err(Error::Owner);
+        }
+
+        let connection = Arc::new(connection);
+        let stop = Arc::new(AtomicBool::new(false));
+        let handle = {
+            let (connection, stop) = (Arc::clone(&connection), Arc::clone(&stop));
+            thread::spawn(move || serve(&connection, window, incr, script, &stop))
+        };
+
+        Ok(FakeOwner { connection, window, selection, stop, handle: Some(handle) })
+    }
+
+    pub fn selection(&self) -> Atom {
+        self.selection
+    }
+
+    pub fn window(&self) -> Window {
+        self.window
+    }
+}
+
+impl Drop for FakeOwner {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+        let _ = self.connection.destroy_window(self.window);
+        let _ = self.connection.flush();
+    }
+}
+
+fn serve(connection: &RustConnection, window: Window, incr: Atom, script: Vec<Behavior>, stop: &AtomicBool) {
+    let mut script = script.into_iter();
+    let mut last = Behavior::Ignore;
+    let mut transfers: Vec<Transfer> = Vec::new();
+
+    while !stop.load(Ordering::Relaxed) {
+        let event = match connection.poll_for_event() {
+            Ok(Some(event)) => event,
+            Ok(None) => {
+                thread::sleep(Duration::from_millis(5));
+                continue
+            },
+            Err(_) => return
+        };
+
+        match event {
+            Event::SelectionRequest(event) if event.owner == window => {
+                if let Some(behavior) = script.next() {
+                    last = behavior;
+                }
+                if let Some(transfer) = answer(connection, incr, &event, &last) {
+                    transfers.push(transfer);
+                }
+            },
+            Event::PropertyNotify(event) if event.state == Property::DELETE => {
+                let i = match transfers.iter()
+                    .position(|transfer| transfer.requestor == event.window && transfer.property == event.atom)
+                {
+                    Some(i) => i,
+                    None => continue
+                };
+                if !send_chunk(connection, &mut transfers[i]) {
+                    transfers.remove(i);
+                }
+            },
+            _ => ()
+        }
+        let _ = connection.flush();
+    }
+}
+
+/// Answer a request, returns the INCR transfer it started.
+fn answer(connection: &RustConnection, incr: Atom, event: &SelectionRequestEvent, behavior: &Behavior)
+    -> Option<Transfer>
+{
+    let change = |type_: Atom, value: &[u8]| x11rb::wrapper::ConnectionExt::change_property8(
+        connection, PropMode::REPLACE, event.requestor, event.property, type_, value
+    ).map(drop);
+    let start_incr = |size: u32| {
+        connection.change_window_attributes(
+            event.requestor,
+            &ChangeWindowAttributesAux::new().event_mask(EventMask::PROPERTY_CHANGE)
+        )?;
+        x11rb::wrapper::ConnectionExt::change_property32(
+            connection, PropMode::REPLACE, event.requestor, event.property, incr, &[size]
+        ).map(drop)
+    };
+
+    let (property, transfer) = match *behavior {
+        Behavior::Serve(ref value) => (change(event.target, value).ok().map(|_| event.property), None),
+        Behavior::Refuse => (Some(x11rb::NONE), None),
+        Behavior::WrongType(type_, ref value) => (change(type_, value).ok().map(|_| event.property), None),
+        Behavior::StallIncr { ref value, chunks } => {
+            let transfer = Transfer {
+                requestor: event.requestor,
+                property: event.property,
+                target: event.target,
+                value: value.clone(),
+                pos: 0,
+                chunks_left: Some(chunks),
+            };
+            (start_incr(value.len() as u32).ok().map(|_| event.property), Some(transfer))
+        },
+        Behavior::BogusIncrSize { ref value, size } => {
+            let transfer = Transfer {
+                requestor: event.requestor,
+                property: event.property,
+                target: event.target,
+                value: value.clone(),
+                pos: 0,
+                chunks_left: None,
+            };
+            (start_incr(size).ok().map(|_| event.property), Some(transfer))
+        },
+        Behavior::Ignore => (None, None),
+        Behavior::Vanish => {
+            let _ = connection.destroy_window(event.owner);
+            (None, None)
+        }
+    };
+
+    let property = property?;
+    let _ = connection.send_event(
+        false,
+        event.requestor,
+        EventMask::default(),
+        SelectionNotifyEvent {
+            response_type: SELECTION_NOTIFY_EVENT,
+            sequence: 0,
+            time: event.time,
+            requestor: event.requestor,
+            selection: event.selection,
+            target: event.target,
+            property
+        }
+    );
+    transfer
+}
+
+/// Send the next chunk of an INCR transfer, returns `false` once it is over or stalled.
+fn send_chunk(connection: &RustConnection, transfer: &mut Transfer) -> bool {
+    match transfer.chunks_left {
+        Some(0) => return false,
+        Some(ref mut chunks_left) => *chunks_left -= 1,
+        None => ()
+    }
+
+    let len = cmp::min(CHUNK_SIZE, transfer.value.len() - transfer.pos);
+    let _ = x11rb::wrapper::ConnectionExt::change_property8(
+        connection,
+        PropMode::REPLACE,
+        transfer.requestor,
+        transfer.property,
+        transfer.target,
+        &transfer.value[transfer.pos..][..len]
+    );
+    transfer.pos += len;
+    len != 0
+}
+
+impl FakeRequestor {
+    pub fn new(display: Option<&str>) -> Result<FakeRequestor, Error> {
+        let (connection, screen) = RustConnection::connect(display)?;
+        let window = create_window(&connection, screen)?;
+        Ok(FakeRequestor { connection, window })
+    }
+
+    pub fn window(&self) -> Window {
+        self.window
+    }
+
+    /// Intern an atom on the requestor's connection.
+    pub fn atom(&self, name: &str) -> Result<Atom, Error> {
+        intern(&self.connection, name)
+    }
+
+    /// Ask for a conversion, without waiting for the answer.
+    pub fn convert(&self, selection: Atom, target: Atom, property: Atom) -> Result<(), Error> {
+        self.connection.convert_selection(self.window, selection, target, property, CURRENT_TIME)?.check()?;
+        Ok(())
+    }
+
+    /// Wait up to `timeout` for the answer to a conversion, returns its property.
+    pub fn wait_notify(&self, timeout: Duration) -> Result<Atom, Error> {
+        let deadline = Instant::now() + timeout;
+        while Instant::now() < deadline {
+            match self.connection.poll_for_event()? {
+                Some(Event::SelectionNotify(event)) if event.requestor == self.window => return Ok(event.property),
+                Some(_) => (),
+                None => thread::sleep(Duration::from_millis(5))
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    /// Set a property of the requestor window, such as the parameters of a side effect.
+    pub fn write(&self, property: Atom, type_: Atom, value: &[u8]) -> Result<(), Error> {
+        x11rb::wrapper::ConnectionExt::change_property8(
+            &self.connection, PropMode::REPLACE, self.window, property, type_, value
+        )?.check()?;
+        Ok(())
+    }
+
+    /// Read a property of the requestor window, deleting it acknowledges an INCR chunk.
+    pub fn read(&self, property: Atom, delete: bool) -> Result<(Atom, Vec<u8>), Error> {
+        let reply = self.connection.get_property(
+            delete, self.window, property, AtomEnum::ANY, 0, u32::MAX
+        )?.reply()?;
+        Ok((reply.type_, reply.value))
+    }
+
+    /// Destroy the requestor window, as a requestor exiting in the middle of a transfer.
+    pub fn vanish(&self) -> Result<(), Error> {
+        self.connection.destroy_window(self.window)?.check()?;
+        Ok(())
+    }
+}
@@ -1,7 +1,13 @@
 extern crate x11_clipboard;
+extern crate x11rb;
 
+use std::thread;
+use std::sync::Arc;
+use std::sync::mpsc::channel;
 use std::time::{ Instant, Duration };
-use x11_clipboard::Clipboard;
+use x11rb::connection::Connection;
+use x11rb::protocol::xproto::ConnectionExt;
+use x11_clipboard::{ AccessRequest, Clipboard, ClipboardBuilder, Health, RustConnection, Selection };
 
 
 #[test]
@@ -16,18 +22,712 @@ fn it_work() {
     clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
 
     let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
-    assert_eq!(output, data.as_bytes());
+    assert_eq!(output.bytes, data.as_bytes());
 
     let data = format!("{:?}", Instant::now());
     clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
 
     let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
-    assert_eq!(output, data.as_bytes());
+    assert_eq!(output.bytes, data.as_bytes());
 
     let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
-    assert_eq!(output, data.as_bytes());
+    assert_eq!(output.bytes, data.as_bytes());
 
     let dur = Duration::from_secs(3);
     let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, dur).unwrap();
-    assert_eq!(output, data.as_bytes());
+    assert_eq!(output.bytes, data.as_bytes());
+}
+
+#[test]
+fn builder_incr() {
+    let data = vec![0x42; 64 * 1024];
+    let clipboard = ClipboardBuilder::new()
+        .property("X11_CLIPBOARD_TEST_OUT")
+        .incr_threshold(1024)
+        .incr_chunk_size(512)
+        .timeout(Duration::from_secs(3))
+        .build()
+        .unwrap();
+
+    let atom_clipboard = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_INCR").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.setter.atoms.property;
+
+    clipboard.store(atom_clipboard, atom_utf8string, data.clone()).unwrap();
+
+    let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, None).unwrap();
+    assert_eq!(output.bytes, data);
+}
+
+#[test]
+fn shared_connection() {
+    let data = format!("{:?}", Instant::now());
+    let (connection, screen) = RustConnection::connect(None).unwrap();
+    let connection = Arc::new(connection);
+    let clipboard = Arc::new(Clipboard::with_connection(Arc::clone(&connection), screen, None).unwrap());
+
+    let clipboard2 = Arc::clone(&clipboard);
+    thread::spawn(move || loop {
+        let (event, sequence) = connection.wait_for_event_with_sequence().unwrap();
+        clipboard2.handle_event_with_sequence(&event, sequence).unwrap();
+    });
+
+    let atom_clipboard = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_SHARED").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.setter.atoms.property;
+
+    clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
+
+    let dur = Duration::from_secs(3);
+    let output = clipboard.load(atom_clipboard, atom_utf8string, atom_property, dur).unwrap();
+    assert_eq!(output.bytes, data.as_bytes());
+}
+
+#[test]
+fn concurrent_load() {
+    let clipboard = Arc::new(Clipboard::new().unwrap());
+
+    let atom_clipboard = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_CONCURRENT_1").unwrap();
+    let atom_primary = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_CONCURRENT_2").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.setter.atoms.property;
+
+    clipboard.store(atom_clipboard, atom_utf8string, "clipboard").unwrap();
+    clipboard.store(atom_primary, atom_utf8string, "primary").unwrap();
+
+    let handles = (0..8)
+        .map(|i| {
+            let clipboard = Arc::clone(&clipboard);
+            let (selection, expected) = if i % 2 == 0 {
+                (atom_clipboard, "clipboard")
+            } else {
+                (atom_primary, "primary")
+            };
+            thread::spawn(move || {
+                let dur = Duration::from_secs(3);
+                for _ in 0..16 {
+                    let output = clipboard.load(selection, atom_utf8string, atom_property, dur).unwrap();
+                    assert_eq!(output.bytes, expected.as_bytes());
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn status() {
+    let data = format!("{:?}", Instant::now());
+    let clipboard = Clipboard::new().unwrap();
+
+    let atom_clipboard = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_STATUS").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+
+    clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
+
+    let status = clipboard.status().unwrap();
+    assert_eq!(status.health, Health::Running);
+    assert_eq!(status.selections.len(), 1);
+    assert_eq!(status.selections[0].selection, atom_clipboard);
+    assert_eq!(status.selections[0].target, atom_utf8string);
+    assert_eq!(status.selections[0].size, data.len());
+    assert!(status.transfers.is_empty());
+}
+
+#[test]
+fn clear() {
+    let data = format!("{:?}", Instant::now());
+    let clipboard = Clipboard::new().unwrap();
+    let other = Clipboard::new().unwrap();
+
+    let atom_clipboard = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_CLEAR").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+
+    clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
+    clipboard.clear(atom_clipboard).unwrap();
+    assert!(clipboard.status().unwrap().selections.is_empty());
+
+    let owner = clipboard.getter.connection.get_selection_owner(atom_clipboard).unwrap().reply().unwrap().owner;
+    assert_eq!(owner, x11rb::NONE);
+
+    other.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
+    clipboard.clear(atom_clipboard).unwrap();
+
+    let owner = clipboard.getter.connection.get_selection_owner(atom_clipboard).unwrap().reply().unwrap().owner;
+    assert_eq!(owner, x11rb::NONE);
+}
+
+#[test]
+fn shutdown() {
+    let data = format!("{:?}", Instant::now());
+    let clipboard = ClipboardBuilder::new()
+        .incr_threshold(8)
+        .incr_chunk_size(8)
+        .build()
+        .unwrap();
+    let other = Clipboard::new().unwrap();
+
+    let atom_clipboard = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_SHUTDOWN").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = other.getter.atoms.property;
+
+    clipboard.store(atom_clipboard, atom_utf8string, data.as_bytes()).unwrap();
+    let output = other.load(atom_clipboard, atom_utf8string, atom_property, Duration::from_secs(3)).unwrap();
+    assert_eq!(output.bytes, data.as_bytes());
+
+    clipboard.shutdown(Instant::now() + Duration::from_secs(1)).unwrap();
+}
+
+/// A clipboard in the middle of an INCR transfer of `value` to a requestor, with its property.
+#[cfg(feature = "testing")]
+fn incr_transfer(name: &str, value: &[u8])
+    -> (Clipboard, x11_clipboard::testing::FakeRequestor, x11rb::protocol::xproto::Atom)
+{
+    use x11_clipboard::testing::FakeRequestor;
+
+    let clipboard = ClipboardBuilder::new()
+        .incr_threshold(1024)
+        .build()
+        .unwrap();
+    let atom_selection = clipboard.setter.get_atom(name).unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let dur = Duration::from_secs(3);
+
+    clipboard.store(atom_selection, atom_utf8string, value).unwrap();
+
+    let requestor = FakeRequestor::new(None).unwrap();
+    let property = requestor.atom(&format!("{}_PROPERTY", name)).unwrap();
+    requestor.convert(atom_selection, atom_utf8string, property).unwrap();
+    assert_eq!(requestor.wait_notify(dur).unwrap(), property);
+    assert_eq!(requestor.read(property, true).unwrap().0, clipboard.setter.atoms.incr);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(clipboard.status().unwrap().transfers.len(), 1);
+
+    (clipboard, requestor, property)
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn shutdown_drain() {
+    use x11_clipboard::testing::FakeRequestor;
+
+    let value = vec![b'x'; 5000];
+    let (clipboard, requestor, property) = incr_transfer("X11_CLIPBOARD_TEST_SHUTDOWN_DRAIN", &value);
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_SHUTDOWN_DRAIN").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let dur = Duration::from_secs(3);
+
+    let shutdown = thread::spawn(move || clipboard.shutdown(Instant::now() + dur));
+    thread::sleep(Duration::from_millis(100));
+
+    // New requests are refused meanwhile
+    let other = FakeRequestor::new(None).unwrap();
+    other.convert(atom_selection, atom_utf8string, property).unwrap();
+    assert_eq!(other.wait_notify(dur).unwrap(), x11rb::NONE);
+
+    // The transfer in progress goes on until its final empty chunk
+    let deadline = Instant::now() + dur;
+    let mut received = Vec::new();
+    while Instant::now() < deadline {
+        let (type_, chunk) = requestor.read(property, true).unwrap();
+        if type_ == x11rb::NONE {
+            thread::sleep(Duration::from_millis(5));
+        } else if chunk.is_empty() {
+            break;
+        } else {
+            received.extend(chunk);
+        }
+    }
+    assert_eq!(received, value);
+    shutdown.join().unwrap().unwrap();
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn shutdown_abort() {
+    use x11_clipboard::error::Error;
+
+    let value = vec![b'x'; 5000];
+    let (clipboard, requestor, property) = incr_transfer("X11_CLIPBOARD_TEST_SHUTDOWN_ABORT", &value);
+
+    // The requestor stalls, the transfer is aborted at the deadline
+    match clipboard.shutdown(Instant::now() + Duration::from_millis(200)) {
+        Err(Error::Timeout) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    // The final empty chunk is never sent
+    let (_, chunk) = requestor.read(property, true).unwrap();
+    assert!(!chunk.is_empty());
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(requestor.read(property, true).unwrap().0, x11rb::NONE);
+}
+
+#[test]
+fn named_selection() {
+    let data = format!("{:?}", Instant::now());
+    let clipboard = Clipboard::new().unwrap();
+
+    let atom_selection = clipboard.getter.selection_atom(&Selection::Named("X11_CLIPBOARD_TEST_NAMED".into())).unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+
+    clipboard.store("X11_CLIPBOARD_TEST_NAMED", atom_utf8string, data.as_bytes()).unwrap();
+
+    let output = clipboard.load(atom_selection, atom_utf8string, atom_property, Duration::from_secs(3)).unwrap();
+    assert_eq!(output.bytes, data.as_bytes());
+}
+
+#[test]
+fn atom_names() {
+    let clipboard = Clipboard::new().unwrap();
+
+    let atoms = clipboard.getter.get_atoms(&["text/plain", "X11_CLIPBOARD_TEST_ATOM"]).unwrap();
+    assert_eq!(clipboard.setter.get_atom("text/plain").unwrap(), atoms[0]);
+
+    let names = clipboard.getter.get_atom_names(&[atoms[1], clipboard.getter.atoms.utf8_string]).unwrap();
+    assert_eq!(names, ["X11_CLIPBOARD_TEST_ATOM", "UTF8_STRING"]);
+    assert_eq!(clipboard.getter.get_atom_name(clipboard.getter.atoms.primary).unwrap(), "PRIMARY");
+}
+
+#[test]
+fn no_owner_and_empty() {
+    let clipboard = Clipboard::new().unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_EMPTY").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+
+    clipboard.clear(atom_selection).unwrap();
+    match clipboard.load(atom_selection, atom_utf8string, atom_property, Duration::from_secs(3)) {
+        Err(x11_clipboard::error::Error::NoOwner) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    clipboard.store(atom_selection, atom_utf8string, Vec::new()).unwrap();
+    let output = clipboard.load(atom_selection, atom_utf8string, atom_property, Duration::from_secs(3)).unwrap();
+    assert!(output.bytes.is_empty());
+}
+
+#[test]
+fn owner_info() {
+    let clipboard = Clipboard::new().unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_OWNER_INFO").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+
+    clipboard.store(atom_selection, atom_utf8string, "info").unwrap();
+
+    let info = clipboard.owner_info(atom_selection).unwrap();
+    assert_eq!(info.owner, clipboard.setter.window);
+    assert_eq!(info.window, clipboard.setter.window);
+    assert!(info.class.is_none());
+}
+
+#[test]
+fn store_secret() {
+    let clipboard = ClipboardBuilder::new().info_targets(false).build().unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_SECRET").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_timestamp = clipboard.setter.get_atom("TIMESTAMP").unwrap();
+    let dur = Duration::from_secs(3);
+
+    clipboard.store(atom_selection, atom_utf8string, "public").unwrap();
+    assert_eq!(clipboard.targets(atom_selection, dur).unwrap(), [clipboard.getter.atoms.targets, atom_timestamp, atom_utf8string]);
+    assert!(!clipboard.is_sensitive(atom_selection, dur).unwrap());
+
+    clipboard.store_secret(atom_selection, atom_utf8string, "hunter2").unwrap();
+    assert!(clipboard.is_sensitive(atom_selection, dur).unwrap());
+
+    let atom_property = clipboard.getter.atoms.property;
+    let (data, hints) = clipboard.load_with_hints(atom_selection, atom_utf8string, atom_property, dur).unwrap();
+    assert_eq!(data.bytes, b"hunter2");
+    assert!(hints.sensitive);
+
+    clipboard.store(atom_selection, atom_utf8string, "public").unwrap();
+    let (data, hints) = clipboard.load_with_hints(atom_selection, atom_utf8string, atom_property, dur).unwrap();
+    assert_eq!(data.bytes, b"public");
+    assert!(!hints.sensitive);
+}
+
+#[test]
+fn load_wait_with_hints() {
+    let clipboard = Arc::new(Clipboard::new().unwrap());
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_WAIT_SECRET").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+
+    let (sender, receiver) = channel();
+    {
+        let clipboard = Arc::clone(&clipboard);
+        thread::spawn(move || {
+            let _ = sender.send(clipboard.load_wait_with_hints(atom_selection, atom_utf8string, atom_property));
+        });
+    }
+
+    thread::sleep(Duration::from_millis(200));
+    clipboard.store_secret(atom_selection, atom_utf8string, "hunter2").unwrap();
+
+    let (data, hints) = receiver.recv_timeout(Duration::from_secs(3)).unwrap().unwrap();
+    assert_eq!(data.bytes, b"hunter2");
+    assert!(hints.sensitive);
+}
+
+#[test]
+fn store_with_ttl() {
+    let clipboard = Clipboard::new().unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_TTL").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+
+    clipboard.store_with_ttl(atom_selection, atom_utf8string, "once", Duration::from_millis(200)).unwrap();
+    let output = clipboard.load(atom_selection, atom_utf8string, atom_property, Duration::from_secs(3)).unwrap();
+    assert_eq!(output.bytes, b"once");
+
+    thread::sleep(Duration::from_millis(500));
+    assert!(clipboard.status().unwrap().selections.is_empty());
+    let owner = clipboard.getter.connection.get_selection_owner(atom_selection).unwrap().reply().unwrap().owner;
+    assert_eq!(owner, x11rb::NONE);
+}
+
+#[test]
+fn store_limited() {
+    let clipboard = Clipboard::new().unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_LIMITED").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+    let dur = Duration::from_secs(3);
+
+    match clipboard.store_limited(atom_selection, atom_utf8string, "never", 0) {
+        Err(x11_clipboard::error::Error::NoReads) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+
+    clipboard.store_limited(atom_selection, atom_utf8string, "twice", 2).unwrap();
+    clipboard.targets(atom_selection, dur).unwrap();
+    for _ in 0..2 {
+        let output = clipboard.load(atom_selection, atom_utf8string, atom_property, dur).unwrap();
+        assert_eq!(output.bytes, b"twice");
+    }
+
+    // The owner gives up the selection after answering
+    thread::sleep(Duration::from_millis(100));
+    match clipboard.load(atom_selection, atom_utf8string, atom_property, dur) {
+        Err(x11_clipboard::error::Error::NoOwner) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn audit() {
+    let (sender, receiver) = channel();
+    let clipboard = ClipboardBuilder::new()
+        .audit(sender)
+        .build()
+        .unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_AUDIT").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+
+    clipboard.store(atom_selection, atom_utf8string, "audited").unwrap();
+    clipboard.load(atom_selection, atom_utf8string, atom_property, Duration::from_secs(3)).unwrap();
+
+    let access = receiver.recv_timeout(Duration::from_secs(3)).unwrap();
+    assert_eq!(access.requestor, clipboard.getter.window);
+    assert_eq!(access.selection, atom_selection);
+    assert_eq!(access.target, atom_utf8string);
+    assert_eq!(access.bytes, 7);
+    assert!(!access.incr);
+}
+
+#[test]
+fn access_policy() {
+    let clipboard = ClipboardBuilder::new()
+        .access_policy(|request: &AccessRequest| !request.secret)
+        .build()
+        .unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_POLICY").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+    let dur = Duration::from_secs(3);
+
+    clipboard.store(atom_selection, atom_utf8string, "public").unwrap();
+    let output = clipboard.load(atom_selection, atom_utf8string, atom_property, dur).unwrap();
+    assert_eq!(output.bytes, b"public");
+
+    clipboard.store_secret(atom_selection, atom_utf8string, "hunter2").unwrap();
+    match clipboard.load(atom_selection, atom_utf8string, atom_property, dur) {
+        Err(x11_clipboard::error::Error::ConversionRefused { target }) => assert_eq!(target, atom_utf8string),
+        other => panic!("unexpected result: {:?}", other)
+    }
+}
+
+#[test]
+fn store_targets() {
+    let clipboard = ClipboardBuilder::new().info_targets(false).build().unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_TARGETS").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_html = clipboard.setter.get_atom("text/html").unwrap();
+    let atom_timestamp = clipboard.setter.get_atom("TIMESTAMP").unwrap();
+    let atom_property = clipboard.getter.atoms.property;
+    let dur = Duration::from_secs(3);
+
+    clipboard.store_targets(atom_selection, vec![
+        (atom_utf8string, b"text".to_vec()),
+        (atom_html, b"<b>text</b>".to_vec()),
+    ]).unwrap();
+
+    let targets = clipboard.targets(atom_selection, dur).unwrap();
+    assert_eq!(targets, [clipboard.getter.atoms.targets, atom_timestamp, atom_utf8string, atom_html]);
+    assert_eq!(clipboard.load(atom_selection, atom_utf8string, atom_property, dur).unwrap().bytes, b"text");
+    assert_eq!(clipboard.load(atom_selection, atom_html, atom_property, dur).unwrap().bytes, b"<b>text</b>");
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn hostile_owner() {
+    use x11_clipboard::error::Error;
+    use x11_clipboard::testing::{ Behavior, FakeOwner };
+
+    let clipboard = Clipboard::new().unwrap();
+    let atom_utf8string = clipboard.getter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+    let dur = Duration::from_secs(1);
+    let value = vec![b'x'; 5000];
+
+    let owner = FakeOwner::new(None, "X11_CLIPBOARD_TEST_HOSTILE", vec![
+        Behavior::Serve(b"hello".to_vec()),
+        Behavior::Refuse,
+        Behavior::WrongType(clipboard.getter.atoms.string, b"hello".to_vec()),
+        Behavior::BogusIncrSize { value: value.clone(), size: u32::MAX },
+        Behavior::StallIncr { value: value.clone(), chunks: 2 },
+        Behavior::Ignore,
+    ]).unwrap();
+    let atom_selection = owner.selection();
+    let load = || clipboard.load(atom_selection, atom_utf8string, atom_property, dur);
+
+    assert_eq!(load().unwrap().bytes, b"hello");
+    match load() {
+        Err(Error::ConversionRefused { target }) => assert_eq!(target, atom_utf8string),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    let data = load().unwrap();
+    assert_eq!((data.type_, data.bytes), (clipboard.getter.atoms.string, b"hello".to_vec()));
+    assert_eq!(load().unwrap().bytes, value);
+    match load() {
+        Err(Error::Timeout) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    match load() {
+        Err(Error::Timeout) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    drop(owner);
+
+    let owner = FakeOwner::new(None, "X11_CLIPBOARD_TEST_VANISH", vec![Behavior::Vanish]).unwrap();
+    match clipboard.load(owner.selection(), atom_utf8string, atom_property, dur) {
+        Err(Error::Timeout) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
+    let reply = clipboard.getter.connection.get_selection_owner(owner.selection()).unwrap().reply().unwrap();
+    assert_eq!(reply.owner, x11rb::NONE);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn hostile_requestor() {
+    use x11_clipboard::testing::FakeRequestor;
+
+    let clipboard = ClipboardBuilder::new()
+        .incr_threshold(1024)
+        .build()
+        .unwrap();
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_REQUESTOR").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_property = clipboard.getter.atoms.property;
+    let dur = Duration::from_secs(3);
+    let value = vec![b'x'; 5000];
+
+    clipboard.store(atom_selection, atom_utf8string, value.clone()).unwrap();
+
+    let requestor = FakeRequestor::new(None).unwrap();
+    let property = requestor.atom("X11_CLIPBOARD_TEST_REQUESTOR_PROPERTY").unwrap();
+    let selection = requestor.atom("X11_CLIPBOARD_TEST_REQUESTOR").unwrap();
+    let target = requestor.atom("UTF8_STRING").unwrap();
+    let incr = requestor.atom("INCR").unwrap();
+
+    requestor.convert(selection, target, property).unwrap();
+    assert_eq!(requestor.wait_notify(dur).unwrap(), property);
+    assert_eq!(requestor.read(property, true).unwrap().0, incr);
+    thread::sleep(Duration::from_millis(100));
+    assert_eq!(clipboard.status().unwrap().transfers.len(), 1);
+
+    // Leave in the middle of the transfer
+    requestor.vanish().unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(clipboard.status().unwrap().transfers.is_empty());
+    assert_eq!(clipboard.load(atom_selection, atom_utf8string, atom_property, dur).unwrap().bytes, value);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn side_effects() {
+    use std::sync::Mutex;
+    use x11_clipboard::{ SideEffect, SideEffectRequest };
+    use x11_clipboard::testing::FakeRequestor;
+
+    let effects = Arc::new(Mutex::new(Vec::new()));
+    let clipboard = {
+        let effects = Arc::clone(&effects);
+        ClipboardBuilder::new()
+            .side_effects(move |request: &SideEffectRequest| {
+                // Refuse to delete twice
+                let mut effects = effects.lock().unwrap();
+                let first = !effects.contains(&request.effect);
+                effects.push(request.effect.clone());
+                first
+            })
+            .build()
+            .unwrap()
+    };
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_EFFECTS").unwrap();
+    let atoms = clipboard.setter.atoms.clone();
+    let dur = Duration::from_secs(3);
+
+    clipboard.store(atom_selection, atoms.utf8_string, "text").unwrap();
+    let targets = clipboard.targets(atom_selection, dur).unwrap();
+    assert!(targets.contains(&atoms.delete) && targets.contains(&atoms.insert_property));
+
+    let requestor = FakeRequestor::new(None).unwrap();
+    let property = requestor.atom("X11_CLIPBOARD_TEST_EFFECTS_PROPERTY").unwrap();
+    requestor.write(property, atoms.utf8_string, b"inserted").unwrap();
+    requestor.convert(atom_selection, atoms.insert_property, property).unwrap();
+    assert_eq!(requestor.wait_notify(dur).unwrap(), property);
+    assert_eq!(requestor.read(property, true).unwrap(), (atoms.null, Vec::new()));
+
+    requestor.convert(atom_selection, atoms.delete, property).unwrap();
+    assert_eq!(requestor.wait_notify(dur).unwrap(), property);
+    requestor.convert(atom_selection, atoms.delete, property).unwrap();
+    assert_eq!(requestor.wait_notify(dur).unwrap(), x11rb::NONE);
+
+    assert_eq!(*effects.lock().unwrap(), [
+        SideEffect::InsertProperty { type_: atoms.utf8_string, value: b"inserted".to_vec() },
+        SideEffect::Delete,
+        SideEffect::Delete,
+    ]);
+}
+
+#[cfg(feature = "testing")]
+#[test]
+fn info_targets() {
+    use x11_clipboard::testing::FakeRequestor;
+
+    let clipboard = Clipboard::new().unwrap();
+    let private = ClipboardBuilder::new().info_targets(false).build().unwrap();
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_INFO").unwrap();
+    let atom_private = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_INFO_PRIVATE").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let dur = Duration::from_secs(3);
+
+    clipboard.store(atom_selection, atom_utf8string, "text").unwrap();
+    private.store(atom_private, atom_utf8string, "text").unwrap();
+
+    let requestor = FakeRequestor::new(None).unwrap();
+    let property = requestor.atom("X11_CLIPBOARD_TEST_INFO_PROPERTY").unwrap();
+    let process = requestor.atom("PROCESS").unwrap();
+    let length = requestor.atom("LENGTH").unwrap();
+    assert!(clipboard.targets(atom_selection, dur).unwrap().contains(&process));
+    assert!(!private.targets(atom_private, dur).unwrap().contains(&process));
+
+    requestor.convert(atom_selection, process, property).unwrap();
+    assert_eq!(requestor.wait_notify(dur).unwrap(), property);
+    let (_, value) = requestor.read(property, true).unwrap();
+    assert_eq!(value, std::process::id().to_ne_bytes());
+
+    requestor.convert(atom_selection, length, property).unwrap();
+    assert_eq!(requestor.wait_notify(dur).unwrap(), property);
+    let (_, value) = requestor.read(property, true).unwrap();
+    assert_eq!(value, 4u32.to_ne_bytes());
+
+    requestor.convert(atom_private, process, property).unwrap();
+    assert_eq!(requestor.wait_notify(dur).unwrap(), property);
+    let (_, value) = requestor.read(property, true).unwrap();
+    assert_eq!(value, b"text");
+}
+
+#[test]
+fn selection_data() {
+    let clipboard = Clipboard::new().unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_DATA").unwrap();
+    let atom_utf8string = clipboard.setter.atoms.utf8_string;
+    let atom_targets = clipboard.getter.atoms.targets;
+    let atom_timestamp = clipboard.setter.get_atom("TIMESTAMP").unwrap();
+    let atom_property = clipboard.getter.atoms.property;
+    let dur = Duration::from_secs(3);
+
+    clipboard.store(atom_selection, atom_utf8string, "text").unwrap();
+
+    let data = clipboard.load(atom_selection, atom_utf8string, atom_property, dur).unwrap();
+    assert_eq!((data.type_, data.format), (atom_utf8string, 8));
+    assert_eq!(data.as_str(), Some("text"));
+    assert_eq!(data.as_atoms(), None);
+
+    let data = clipboard.load(atom_selection, atom_targets, atom_property, dur).unwrap();
+    assert_eq!(data.format, 32);
+    assert_eq!(data.as_atoms().unwrap()[..3], [atom_targets, atom_timestamp, atom_utf8string]);
+
+    // The owner tells when it took the selection
+    let data = clipboard.load(atom_selection, atom_timestamp, atom_property, dur).unwrap();
+    assert_eq!((data.type_, data.format), (x11rb::protocol::xproto::AtomEnum::INTEGER.into(), 32));
+    assert!(data.as_u32s().unwrap()[0] > 0);
+}
+
+#[test]
+fn store_data() {
+    use x11_clipboard::SelectionData;
+
+    let clipboard = ClipboardBuilder::new()
+        .incr_threshold(16)
+        .incr_chunk_size(7)
+        .build()
+        .unwrap();
+
+    let atom_selection = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_STORE_DATA").unwrap();
+    let atom_target = clipboard.setter.get_atom("X11_CLIPBOARD_TEST_STORE_DATA_TARGET").unwrap();
+    let atom_property = clipboard.getter.atoms.property;
+    let atom_atom = x11rb::protocol::xproto::AtomEnum::ATOM.into();
+    let atom_integer = x11rb::protocol::xproto::AtomEnum::INTEGER.into();
+    let dur = Duration::from_secs(3);
+
+    let atoms = [clipboard.getter.atoms.primary, clipboard.getter.atoms.clipboard];
+    clipboard.store_data(atom_selection, atom_target, SelectionData::from_u32s(atom_atom, &atoms)).unwrap();
+    // The type of the value is kept, whatever the target
+    let data = clipboard.load(atom_selection, atom_target, atom_property, dur).unwrap();
+    assert_eq!((data.type_, data.as_atoms().unwrap()), (atom_atom, atoms.to_vec()));
+    // The only value answers any target
+    let data = clipboard.load(atom_selection, atom_atom, atom_property, dur).unwrap();
+    assert_eq!(data.as_atoms().unwrap(), atoms);
+
+    // Sent with INCR, in chunks of whole elements
+    let values = (0..20).collect::<Vec<u16>>();
+    clipboard.store_data(atom_selection, atom_integer, SelectionData::from_u16s(atom_integer, &values)).unwrap();
+    let data = clipboard.load(atom_selection, atom_integer, atom_property, dur).unwrap();
+    assert_eq!((data.format, data.as_u16s().unwrap()), (16, values));
+
+    let data = SelectionData { type_: atom_integer, format: 32, bytes: vec![0; 6] };
+    match clipboard.store_data(atom_selection, atom_integer, data) {
+        Err(x11_clipboard::error::Error::InvalidFormat(32)) => (),
+        other => panic!("unexpected result: {:?}", other)
+    }
 }